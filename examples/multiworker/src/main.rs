@@ -49,7 +49,8 @@ impl Component for App {
             Message::AddWorker => {
                 let id = self.rng.gen::<u32>();
                 let mut bridge = Worker::bridge(self.link.callback(Message::WorkerMessage));
-                bridge.send(worker::Input::Initialize(id));
+                // The first worker starts the cluster, and the rest join it
+                bridge.send(worker::Input::Initialize(id, self.workers.is_empty()));
                 self.workers.insert(id, bridge);
                 self.states.insert(id, Role::Follower);
                 true
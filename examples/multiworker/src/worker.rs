@@ -16,7 +16,8 @@ pub struct NodeMsg {}
 
 #[derive(Serialize, Deserialize)]
 pub enum Input {
-    Initialize(u32),
+    /// The node's id, and whether it bootstraps the cluster
+    Initialize(u32, bool),
     Send(NodeMsg),
     Stop,
 }
@@ -69,23 +70,31 @@ impl Agent for Worker {
 
     fn handle_input(&mut self, msg: Self::Input, _id: HandlerId) {
         match msg {
-            Input::Initialize(id) => {
+            Input::Initialize(id, bootstrap) => {
                 let on_received = self.link.callback(Message::NodePayload);
                 let on_role_change = self.link.callback(Message::RoleChange);
-                self.node = Some(
-                    Node::builder()
-                        .id(id)
-                        .channel("node-workers")
-                        .election_timeout_range(500, 1000)
-                        .on_received(move |message| on_received.emit(message))
-                        .on_role_change(move |role| on_role_change.emit(role))
-                        .build(),
-                );
+                let mut builder = Node::builder()
+                    .id(id)
+                    .channel("node-workers")
+                    .election_timeout_range(500, 1000)
+                    .on_received(move |message| on_received.emit(message))
+                    .on_role_change(move |role| on_role_change.emit(role));
+                if bootstrap {
+                    builder = builder.bootstrap();
+                }
+                self.node = Some(builder.build());
             }
 
             Input::Send(msg) => {
                 if let Some(node) = self.node.clone() {
-                    node.issue(msg)
+                    let proposal = node.propose(msg);
+                    wasm_bindgen_futures::spawn_local(async move {
+                        if let Err(error) = proposal.await {
+                            yew::services::ConsoleService::log(
+                                format!("proposal failed: {}", error).as_str(),
+                            );
+                        }
+                    });
                 }
             }
 
@@ -9,32 +9,117 @@
 //! When any follower's election times out, they
 //! // TODO
 //!
+//! ### Log Replication
+//!
+//! Payloads issued through the leader are appended to its log and sent to the
+//! other nodes with `AppendEntries` messages (which double as heartbeats).
+//! Payloads proposed on any other node are forwarded to the leader first.
+//! Each node proposes under its own client session, numbering its proposals,
+//! so a proposal that is retried (e.g. after a leader change) is only applied
+//! once.
+//! Once a majority of nodes have acknowledged an entry, it is committed and
+//! each node passes it to `on_received`, in log order.
+//!
+//! ### Membership Changes
+//!
+//! The set of voters is stored in the log, so majorities are always counted
+//! against a configuration the cluster agreed on. A cluster starts from the
+//! single node built with [`NodeBuilder::bootstrap`], whose initial
+//! configuration has only itself as a voter. Nodes that join or leave the
+//! channel are added or removed by the leader using joint consensus: first a
+//! configuration containing both the old and the new voters is committed
+//! (which needs a majority of each), and then the new configuration alone.
+//! Alternatively, with [`MembershipChanges::SingleServer`], voters are added or
+//! removed one at a time. Either way, a joining node is sent the log as a
+//! non-voter until it has caught up, and only then counts towards quorum.
+//! Peers that stop answering the leader's heartbeats (like crashed or frozen
+//! tabs) are evicted the same way, and added back if they're heard from again.
+//!
 
+use futures_channel::oneshot;
 use gloo::{events::EventListener, timers::callback::Timeout};
 use rand::{rngs::OsRng, Rng};
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
     sync::{Arc, Mutex},
 };
 use web_sys::BroadcastChannel;
 
+mod liveness;
+mod log;
+mod membership;
+mod progress;
+mod propose;
 mod raft;
+mod read;
 mod rpc;
-
-use raft::Peer;
-pub use raft::Role;
+mod session;
+mod state_machine;
+mod storage;
+
+use log::Log;
+pub use log::{Entry, EntryPayload, Snapshot};
+use membership::MembershipDone;
+pub use membership::{Membership, MembershipChanges, MembershipError};
+use progress::Progress;
+use propose::PendingProposal;
+pub use propose::{ProposeError, Proposed};
+pub use raft::{Peer, Role};
+use read::PendingRead;
+pub use read::ReadError;
 use rpc::{Message, Recipient};
+pub use session::Sessions;
+use state_machine::DynStateMachine;
+pub use state_machine::StateMachine;
+#[cfg(not(target_arch = "wasm32"))]
+pub use storage::FileStorage;
+#[cfg(feature = "indexeddb")]
+pub use storage::IndexedDbStorage;
+pub use storage::{HardState, InitialState, MemoryStorage, Storage};
 
 pub struct Node<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     pub id: u32, // TODO: better type? String? uuid?
 
+    /// The first election timeout drawn, in ms. Timeouts are now drawn afresh
+    /// each time the election timer starts, so this is never read.
+    #[deprecated(note = "election timeouts are redrawn each time; use `election_timeout_range`")]
     pub election_timeout_ms: u32,
+    /// Each election timeout is drawn from this range (in ms)
+    election_timeout_ms_range: (u32, u32),
     pub heartbeat_timeout_ms: u32,
-
-    state: Arc<Mutex<NodeState>>,
+    pub pre_vote: bool,
+    /// Lease reads are enabled when this is set
+    pub lease_drift_ms: Option<u32>,
+    pub membership_changes: MembershipChanges,
+    /// Whether configurations this node appends (as leader) name it as their
+    /// tie-breaker
+    pub tie_breaker: bool,
+    /// The leader suspects peers that haven't answered for this long
+    pub suspect_after_ms: u32,
+    /// The leader evicts peers that haven't answered for this long (0 never
+    /// evicts)
+    pub evict_after_ms: u32,
+    /// Client sessions with no entries in this many log entries expire
+    pub session_expiry: u32,
+
+    pub snapshot_threshold: u32,
+    pub snapshot_retain: u32,
+
+    /// Most unacknowledged `AppendEntries` the leader sends to one follower
+    pub max_inflight_appends: u32,
+    /// Most entries the leader sends in one `AppendEntries`
+    pub max_append_entries: u32,
+    /// How long the leader holds new entries back to batch them (0 sends
+    /// each straight away)
+    pub batch_delay_ms: u32,
+    /// Most entries the leader holds back for one batch
+    pub max_batch_entries: u32,
+
+    state: Arc<Mutex<NodeState<T>>>,
 
     channel: Arc<BroadcastChannel>,
 
@@ -44,30 +129,146 @@ where
     on_role_change: Option<Box<dyn Fn(Role) + 'static>>,
 }
 
-pub(crate) struct NodeState {
+pub(crate) struct NodeState<T> {
     role: raft::Role,
     term: u32,
     voted_for: Option<Peer>,
 
+    /// The leader we last heard from, cleared when the election timer fires
+    leader: Option<Peer>,
     votes: HashSet<Peer>,
+    pre_votes: HashSet<Peer>,
+    /// Elections in a row that ended without a leader, to back off after
+    failed_elections: u32,
+    /// Every node seen on the channel
     peers: HashSet<Peer>,
+    /// Nodes seen on the channel that joined as learners
+    learners: HashSet<Peer>,
+    /// The latest configuration in the log (or snapshot), if there is one
+    membership: Option<Membership>,
+    /// Index of the entry holding `membership`
+    membership_index: u32,
+    /// Peers that have joined or left the channel, but haven't been added to
+    /// or removed from the configuration yet
+    joining: HashSet<Peer>,
+    leaving: HashSet<Peer>,
+    /// (Leader only) Resolved when the current membership change completes
+    membership_change: Option<MembershipDone>,
+
+    log: Log<T>,
+    /// Latest snapshot of the state machine, covering the compacted log
+    snapshot: Option<Snapshot>,
+    /// Highest log index known to be committed
+    commit_index: u32,
+    /// Highest log index applied to the state machine (and passed to
+    /// `on_received`)
+    last_applied: u32,
+    /// (Leader only) Next log index to send to each peer
+    next_index: HashMap<Peer, u32>,
+    /// (Leader only) Highest log index known to be replicated on each peer
+    match_index: HashMap<Peer, u32>,
+    /// (Leader only) How entries are being sent to each peer
+    progress: HashMap<Peer, Progress>,
+    /// (Leader only) Entries appended but held back for the current batch
+    batched: u32,
+    /// (Leader only) Peers that have responded since the last quorum check
+    recent_peers: HashSet<Peer>,
+    /// (Leader only) When each peer last responded, in ms since the epoch
+    last_seen: HashMap<Peer, f64>,
+    /// (Leader only) Peers that haven't responded for a while
+    suspects: HashSet<Peer>,
+    /// (Leader only) Learners evicted for going silent, which are readmitted
+    /// as learners if they're heard from again
+    evicted_learners: HashSet<Peer>,
+    /// (Leader only) Incremented each time entries (or heartbeats) are sent,
+    /// and echoed back in responses
+    heartbeat_round: u64,
+    /// (Leader only) When each recent round was sent, for the lease
+    round_sent: BTreeMap<u64, f64>,
+    /// (Leader only) The latest round each peer has acknowledged
+    acked_round: HashMap<Peer, u64>,
+    /// (Leader only) Until when lease reads can be served, in ms since the
+    /// epoch
+    lease_expires: f64,
+    /// (Leader only) Reads waiting to be confirmed and applied
+    reads: Vec<PendingRead>,
+    /// This node's client session, which its proposals are made under
+    session: u64,
+    /// Sequence number for the next proposal in `session`
+    next_seq: u64,
+    /// Our proposals waiting to be applied, by sequence number
+    proposals: BTreeMap<u64, PendingProposal<T>>,
+    /// Latest results applied for every client session
+    sessions: Sessions,
+    /// (Leader only) The peer leadership is being handed over to
+    transfer_target: Option<Peer>,
+
+    state_machine: Option<Box<dyn DynStateMachine<T>>>,
+    storage: Box<dyn Storage<T>>,
 
     election_task: Option<Timeout>,
     heartbeat_task: Option<Timeout>,
+    quorum_task: Option<Timeout>,
+    transfer_task: Option<Timeout>,
+    batch_task: Option<Timeout>,
+    /// Whether applying committed entries is already scheduled
+    apply_scheduled: bool,
     channel_listener: Option<EventListener>,
 }
 
-impl Default for NodeState {
+impl<T> Default for NodeState<T>
+where
+    T: Clone + 'static,
+{
     fn default() -> Self {
         Self {
             role: Role::Follower,
             term: 0,
             voted_for: None,
+            leader: None,
             votes: HashSet::new(),
+            pre_votes: HashSet::new(),
+            failed_elections: 0,
             peers: HashSet::new(),
+            learners: HashSet::new(),
+            membership: None,
+            membership_index: 0,
+            joining: HashSet::new(),
+            leaving: HashSet::new(),
+            membership_change: None,
+
+            log: Log::default(),
+            snapshot: None,
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            progress: HashMap::new(),
+            batched: 0,
+            recent_peers: HashSet::new(),
+            last_seen: HashMap::new(),
+            suspects: HashSet::new(),
+            evicted_learners: HashSet::new(),
+            heartbeat_round: 0,
+            round_sent: BTreeMap::new(),
+            acked_round: HashMap::new(),
+            lease_expires: 0.0,
+            reads: Vec::new(),
+            session: 0,
+            next_seq: 1,
+            proposals: BTreeMap::new(),
+            sessions: Sessions::default(),
+            transfer_target: None,
+
+            state_machine: None,
+            storage: Box::new(MemoryStorage::default()),
 
             election_task: None,
             heartbeat_task: None,
+            quorum_task: None,
+            transfer_task: None,
+            batch_task: None,
+            apply_scheduled: false,
             channel_listener: None,
         }
     }
@@ -77,10 +278,25 @@ pub struct NodeBuilder<T> {
     election_timeout_ms: Option<u32>,
     election_timeout_ms_range: Option<(u32, u32)>,
     heartbeat_timeout_ms: Option<u32>,
+    pre_vote: Option<bool>,
+    lease_drift_ms: Option<u32>,
+    learner: bool,
+    bootstrap: bool,
+    membership_changes: Option<MembershipChanges>,
+    tie_breaker: bool,
+    failure_detection: Option<(u32, u32)>,
+    session_expiry: Option<u32>,
+    snapshot_threshold: Option<u32>,
+    snapshot_retain: Option<u32>,
+    max_inflight_appends: Option<u32>,
+    max_append_entries: Option<u32>,
+    batching: Option<(u32, u32)>,
     id: Option<u32>,
     channel_name: Option<String>,
     on_received_handler: Option<Box<dyn Fn(T) + 'static>>,
     on_role_change_handler: Option<Box<dyn Fn(Role) + 'static>>,
+    state_machine: Option<Box<dyn DynStateMachine<T>>>,
+    storage: Option<Box<dyn Storage<T>>>,
 }
 
 impl<T> Default for NodeBuilder<T> {
@@ -89,17 +305,32 @@ impl<T> Default for NodeBuilder<T> {
             election_timeout_ms: None,
             election_timeout_ms_range: None,
             heartbeat_timeout_ms: None,
+            pre_vote: None,
+            lease_drift_ms: None,
+            learner: false,
+            bootstrap: false,
+            membership_changes: None,
+            tie_breaker: false,
+            failure_detection: None,
+            session_expiry: None,
+            snapshot_threshold: None,
+            snapshot_retain: None,
+            max_inflight_appends: None,
+            max_append_entries: None,
+            batching: None,
             id: None,
             channel_name: None,
             on_received_handler: None,
             on_role_change_handler: None,
+            state_machine: None,
+            storage: None,
         }
     }
 }
 
 impl<T> NodeBuilder<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     /// Set the election timeout in milliseconds. This is discouraged, as the
     /// timeout should be randomized (so that nodes are less likely to
@@ -114,7 +345,11 @@ where
         self
     }
 
-    /// Set the range for the randomized value of the election timeout.
+    /// Set the range for the randomized value of the election timeout. A new
+    /// value is drawn from the range every time the election timer is
+    /// started, and doubled for each election in a row that ends without a
+    /// leader (up to 8 times the drawn value), so nodes that collide once are
+    /// unlikely to collide again.
     ///
     /// Defaults to (150, 300).
     pub fn election_timeout_range(mut self, low_ms: u32, high_ms: u32) -> Self {
@@ -134,6 +369,164 @@ where
         self
     }
 
+    /// Enable or disable the pre-vote phase. With pre-vote, a node whose
+    /// election times out first checks that a majority would vote for it
+    /// before incrementing its term, so a node that was cut off (like a frozen
+    /// background tab) can't force a healthy leader to step down.
+    ///
+    /// Defaults to true
+    pub fn pre_vote(mut self, enabled: bool) -> Self {
+        self.pre_vote = Some(enabled);
+        self
+    }
+
+    /// Enable lease reads (see [`read_with_lease`](Node::read_with_lease)).
+    /// After a majority acknowledges a heartbeat, the leader assumes no other
+    /// leader can be elected for an election timeout (minus
+    /// `drift_margin_ms`) from when it was sent. The margin must cover clock
+    /// drift, and any difference between the nodes' election timeouts.
+    ///
+    /// Lease reads also rely on pre-vote, so are only served when it is
+    /// enabled. Disabled by default.
+    pub fn lease_reads(mut self, drift_margin_ms: u32) -> Self {
+        self.lease_drift_ms = Some(drift_margin_ms);
+        self
+    }
+
+    /// Join the cluster as a learner: the node is sent the log and applies
+    /// committed entries, but never votes or stands for election (until the
+    /// leader promotes it, with
+    /// [`promote_learner`](Node::promote_learner)). Useful for short-lived
+    /// contexts, like iframes or popups.
+    pub fn learner(mut self) -> Self {
+        self.learner = true;
+        self
+    }
+
+    /// Bootstrap a new cluster from this node: if it doesn't have a
+    /// configuration yet (nothing was loaded from storage), it starts out as
+    /// the only voter, and elects itself. Exactly one node should bootstrap a
+    /// cluster. Every other node joins it, and is made a voter by the leader
+    /// once it has caught up. A node that hasn't bootstrapped or joined a
+    /// cluster never stands for election.
+    ///
+    /// Bootstrapping needs durable storage, like `IndexedDbStorage`: with the
+    /// default in-memory storage, reloading the bootstrapping tab loses its
+    /// configuration, so it bootstraps (and elects itself in) a second,
+    /// separate cluster.
+    pub fn bootstrap(mut self) -> Self {
+        self.bootstrap = true;
+        self
+    }
+
+    /// Set how the leader moves between configurations when voters are added
+    /// or removed. See [`MembershipChanges`].
+    ///
+    /// Defaults to [`MembershipChanges::Joint`]
+    pub fn membership_changes(mut self, mode: MembershipChanges) -> Self {
+        self.membership_changes = Some(mode);
+        self
+    }
+
+    /// While this node is the leader, name it as the tie-breaker in each
+    /// configuration it moves to with an even number of voters. Half of the
+    /// voters is then enough for a quorum if it includes the tie-breaker, so
+    /// in a two-node cluster the tie-breaker can still elect itself (and
+    /// commit) when the other node hangs. The other node still needs both votes, and once elected, takes
+    /// the tie-breaker over through a membership change.
+    ///
+    /// The tie-breaker is stored in the configuration, so every node agrees on
+    /// it, and configurations with a tie-breaker are only entered or left
+    /// through joint consensus. So it can't be combined with
+    /// [`MembershipChanges::SingleServer`] (`build` panics). Disabled by
+    /// default
+    pub fn tie_breaker(mut self, enabled: bool) -> Self {
+        self.tie_breaker = enabled;
+        self
+    }
+
+    /// Set how long (in ms) the leader waits to hear from a peer before
+    /// suspecting it has failed, and before evicting it from the
+    /// configuration. Tabs that crash or are frozen by the browser never say
+    /// they're leaving, so would otherwise count towards quorum forever. An
+    /// evicted peer is added back as soon as it is heard from again. An
+    /// `evict_after_ms` of 0 never evicts.
+    ///
+    /// Defaults to (1000, 10000)
+    pub fn failure_detection(mut self, suspect_after_ms: u32, evict_after_ms: u32) -> Self {
+        self.failure_detection = Some((suspect_after_ms, evict_after_ms));
+        self
+    }
+
+    /// Set how many log entries a client session can go without proposing
+    /// before it expires. Retries of a proposal are only recognised as
+    /// duplicates while its session is live, so this should comfortably cover
+    /// how long a proposal can be retried for. Sessions are expired as the log
+    /// is applied, so every node must use the same value. A value of 0 never
+    /// expires sessions.
+    ///
+    /// Defaults to 10000
+    pub fn session_expiry(mut self, entries: u32) -> Self {
+        self.session_expiry = Some(entries);
+        self
+    }
+
+    /// Set how many entries can be applied after the latest snapshot before
+    /// the state machine is snapshotted again and the log is compacted. Only
+    /// takes effect when a [`state_machine`](NodeBuilder::state_machine) is
+    /// attached. A value of 0 disables snapshots.
+    ///
+    /// Defaults to 1000
+    pub fn snapshot_threshold(mut self, entries: u32) -> Self {
+        self.snapshot_threshold = Some(entries);
+        self
+    }
+
+    /// Set how many of the most recent snapshotted entries are kept in the log
+    /// when it is compacted, so that slightly lagging peers can still catch up
+    /// without needing the whole snapshot.
+    ///
+    /// Defaults to 100
+    pub fn snapshot_retain(mut self, entries: u32) -> Self {
+        self.snapshot_retain = Some(entries);
+        self
+    }
+
+    /// Set how many `AppendEntries` messages the leader can have waiting on a
+    /// follower at once. While a follower keeps up, entries are sent as soon
+    /// as they're appended, without waiting for earlier messages to be
+    /// acknowledged. After a follower rejects a message, one is sent at a time
+    /// until its log matches again.
+    ///
+    /// Defaults to 4
+    pub fn max_inflight_appends(mut self, messages: u32) -> Self {
+        self.max_inflight_appends = Some(messages.max(1));
+        self
+    }
+
+    /// Set the most entries the leader sends in a single `AppendEntries`
+    /// message, so a lagging follower is caught up in pieces rather than
+    /// flooding the channel with the whole log.
+    ///
+    /// Defaults to 64
+    pub fn max_append_entries(mut self, entries: u32) -> Self {
+        self.max_append_entries = Some(entries.max(1));
+        self
+    }
+
+    /// Batch entries on the leader: instead of sending each payload that is
+    /// issued or proposed as soon as it's appended, hold it back for up to
+    /// `max_delay_ms`, so that payloads arriving together (e.g. one per
+    /// keystroke) are sent in a single message and committed together. A
+    /// batch is sent early once it holds `max_entries` entries. A delay of 0
+    /// disables batching.
+    ///
+    /// Disabled by default
+    pub fn batching(mut self, max_delay_ms: u32, max_entries: u32) -> Self {
+        self.batching = Some((max_delay_ms, max_entries.max(1)));
+        self
+    }
+
     /// Set the node's id.
     ///
     /// Defaults to a random number
@@ -150,8 +543,9 @@ where
         self
     }
 
-    /// Attach a closure to the node that will be called when the node receives
-    /// a [Message::Payload] message
+    /// Attach a closure to the node that will be called with each issued
+    /// payload, once its log entry has been committed by a majority of nodes.
+    /// It's called without the node's state locked, so it can use the node.
     pub fn on_received<F>(mut self, callback: F) -> Self
     where
         F: Fn(T) + 'static,
@@ -170,17 +564,56 @@ where
         self
     }
 
+    /// Attach a state machine that committed payloads will be applied to, in
+    /// log order. This can be used in place of (or alongside)
+    /// [`on_received`](NodeBuilder::on_received).
+    pub fn state_machine<S>(mut self, state_machine: S) -> Self
+    where
+        S: StateMachine<T> + 'static,
+    {
+        self.state_machine = Some(Box::new(state_machine) as Box<dyn DynStateMachine<T>>);
+        self
+    }
+
+    /// Set where the node persists its term, vote, log and snapshots. Anything
+    /// already in the storage is loaded when the node is built.
+    ///
+    /// Defaults to [`MemoryStorage`], which doesn't survive a reload
+    pub fn storage<S>(mut self, storage: S) -> Self
+    where
+        S: Storage<T> + 'static,
+    {
+        self.storage = Some(Box::new(storage) as Box<dyn Storage<T>>);
+        self
+    }
+
     /// Finalize the builder and construct a Node
+    #[allow(clippy::arc_with_non_send_sync)]
     pub fn build(self) -> Arc<Node<T>> {
         // Deconstruct builder
         let NodeBuilder {
             election_timeout_ms,
             election_timeout_ms_range,
             heartbeat_timeout_ms,
+            pre_vote,
+            lease_drift_ms,
+            learner,
+            bootstrap,
+            membership_changes,
+            tie_breaker,
+            failure_detection,
+            session_expiry,
+            snapshot_threshold,
+            snapshot_retain,
+            max_inflight_appends,
+            max_append_entries,
+            batching,
             id,
             on_received_handler,
             on_role_change_handler,
             channel_name,
+            state_machine,
+            storage,
             ..
         } = self;
 
@@ -194,38 +627,63 @@ where
         };
 
         // Use or generate id
-        let (id, maybe_rng) = if let Some(id) = id {
-            (id, None)
-        } else {
-            let mut rng = OsRng::new().expect("failed to create RNG");
-            (rng.gen(), Some(rng))
+        let id = id.unwrap_or_else(|| OsRng::new().expect("failed to create RNG").gen());
+
+        // A fixed timeout is a range of one value. The timeout itself is drawn
+        // each time the election timer is started.
+        let election_timeout_ms_range = match election_timeout_ms {
+            Some(timeout) => (timeout, timeout),
+            None => election_timeout_ms_range.unwrap_or((150, 300)),
         };
 
-        // Use or generate election_timeout
-        let election_timeout_ms = if let Some(timeout) = election_timeout_ms {
-            timeout
-        } else if let Some((low, high)) = election_timeout_ms_range {
-            if let Some(mut rng) = maybe_rng {
-                rng
-            } else {
-                OsRng::new().expect("failed to create RNG")
-            }
-            .gen_range(low, high)
-        } else {
-            if let Some(mut rng) = maybe_rng {
-                rng
-            } else {
-                OsRng::new().expect("failed to create RNG")
-            }
-            .gen_range(150, 300)
+        let membership_changes = membership_changes.unwrap_or_default();
+        assert!(
+            !(tie_breaker && membership_changes == MembershipChanges::SingleServer),
+            "a tie-breaker needs joint membership changes"
+        );
+
+        let (batch_delay_ms, max_batch_entries) = batching.unwrap_or((0, 1));
+        let (suspect_after_ms, evict_after_ms) = failure_detection.unwrap_or((1000, 10000));
+
+        let mut state = NodeState {
+            session: OsRng::new().expect("failed to create RNG").gen(),
+            state_machine,
+            ..NodeState::default()
         };
+        if let Some(storage) = storage {
+            state.storage = storage;
+        }
+        let initial = state.storage.load();
+        state.restore(initial);
+        if learner {
+            state.learners.insert(Peer::from(id));
+        } else if bootstrap && state.membership.is_none() {
+            state.bootstrap(Peer::from(id));
+        }
 
+        #[allow(deprecated)]
         let node = Node {
             id,
-            election_timeout_ms,
+            election_timeout_ms: raft::draw_election_timeout(election_timeout_ms_range),
+            election_timeout_ms_range,
             heartbeat_timeout_ms: heartbeat_timeout_ms.unwrap_or(50),
-
-            state: Arc::new(Mutex::new(NodeState::default())),
+            pre_vote: pre_vote.unwrap_or(true),
+            lease_drift_ms,
+            membership_changes,
+            tie_breaker,
+            suspect_after_ms,
+            evict_after_ms,
+            session_expiry: session_expiry.unwrap_or(10000),
+
+            snapshot_threshold: snapshot_threshold.unwrap_or(1000),
+            snapshot_retain: snapshot_retain.unwrap_or(100),
+
+            max_inflight_appends: max_inflight_appends.unwrap_or(4),
+            max_append_entries: max_append_entries.unwrap_or(64),
+            batch_delay_ms,
+            max_batch_entries,
+
+            state: Arc::new(Mutex::new(state)),
             channel: Arc::new(channel),
 
             on_received: on_received_handler,
@@ -241,12 +699,17 @@ where
         {
             // let node_ref = node.clone();
             let mut state = node.state.lock().expect("poisoned!");
-            state.election_task = Some(node.clone().new_election_task());
+            node.reset_election_timer(&mut state);
             state.channel_listener = Some(listener);
             state.peers.insert(node.peer());
+            node.update_learner_role(&mut state);
         }
 
-        node.send(Message::PeerAdded, Recipient::Everyone);
+        if learner {
+            node.send(Message::LearnerAdded, Recipient::Everyone);
+        } else {
+            node.send(Message::PeerAdded, Recipient::Everyone);
+        }
 
         node
     }
@@ -254,7 +717,7 @@ where
 
 impl<T> Node<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     const DEFAULT_CHANNEL: &'static str = "raft-nodes";
 
@@ -266,6 +729,13 @@ where
         let mut state = self.state.lock().expect("poisoned!");
         state.replace_election_task(None);
         state.replace_heartbeat_task(None);
+        state.replace_quorum_task(None);
+        state.replace_transfer_task(None);
+        state.replace_batch_task(None);
+        state.apply_scheduled = false;
+        state.fail_reads();
+        state.fail_membership_change(MembershipError::Stopped);
+        state.proposals.clear();
 
         // EventListener drop handles removing listener
         if let Some(listener) = state.channel_listener.take() {
@@ -273,19 +743,160 @@ where
         }
     }
 
+    /// The range election timeouts are drawn from, in ms. See
+    /// [`NodeBuilder::election_timeout_range`].
+    pub fn election_timeout_range(&self) -> (u32, u32) {
+        self.election_timeout_ms_range
+    }
+
+    /// (Leader only) Peers that haven't responded recently, and will be
+    /// evicted if they stay silent
+    pub fn suspects(&self) -> HashSet<Peer> {
+        self.state.lock().expect("poisoned!").suspects.clone()
+    }
+
     pub fn role(&self) -> Role {
         self.state.lock().expect("poisoned!").role
     }
 
+    /// Every node seen on the channel (whether or not it is a voter)
     pub fn peers(&self) -> HashSet<Peer> {
         self.state.lock().expect("poisoned!").peers.clone()
     }
 
-    /// Issue a message to all nodes (only sends if this node is the leader)
-    pub fn issue(&self, payload: T) {
-        if self.role() == Role::Leader {
-            self.send(Message::Payload(payload), Recipient::Everyone)
+    /// The cluster configuration currently in effect
+    pub fn membership(&self) -> Membership {
+        self.state.lock().expect("poisoned!").membership()
+    }
+
+    /// The highest log index known to be committed
+    pub fn commit_index(&self) -> u32 {
+        self.state.lock().expect("poisoned!").commit_index
+    }
+
+    /// The highest log index applied to the state machine
+    pub fn last_applied(&self) -> u32 {
+        self.state.lock().expect("poisoned!").last_applied
+    }
+
+    /// Issue a message to all nodes (only appends to the log if this node is
+    /// the leader, and isn't transferring leadership). Each node receives the
+    /// payload through `on_received` once the entry is committed. With
+    /// [`NodeBuilder::batching`], it is sent along with any other payloads
+    /// issued within the batching window.
+    ///
+    /// See [`propose`](Node::propose) to issue from any node.
+    pub fn issue(self: &Arc<Self>, payload: T) {
+        let mut state = self.state.lock().expect("poisoned!");
+        if state.role == Role::Leader && state.transfer_target.is_none() {
+            state.append_to_log(EntryPayload::Normal(payload));
+            self.replicate_batched(&mut state, 1);
+        }
+    }
+
+    /// Propose a payload from any node. Followers forward it to the leader,
+    /// and hold on to it while there is no leader (retrying whenever a new
+    /// leader is found). Each proposal is numbered within this node's client
+    /// session, so however many times it is retried, it is only applied once.
+    /// Resolves with the index and term it was applied at, and the state
+    /// machine's response, or fails if its entry was lost (see
+    /// [`ProposeError`]). Each node receives the payload through
+    /// `on_received` once the entry is committed.
+    pub fn propose(
+        self: &Arc<Self>,
+        payload: T,
+    ) -> impl Future<Output = Result<Proposed, ProposeError>> {
+        let (done, result) = oneshot::channel();
+        let mut state = self.state.lock().expect("poisoned!");
+        self.start_proposal(&mut state, payload, done);
+        async move { result.await.unwrap_or(Err(ProposeError::Stopped)) }
+    }
+
+    /// (Leader only) Make a linearizable read without writing to the log.
+    /// Resolves with the read index once a majority has confirmed this node
+    /// is still the leader, and the state machine has applied every entry up
+    /// to that index, so its state is at least as new as any committed write.
+    pub fn read_index(self: &Arc<Self>) -> impl Future<Output = Result<u32, ReadError>> {
+        let (done, result) = oneshot::channel();
+        let mut state = self.state.lock().expect("poisoned!");
+        self.start_read(&mut state, done);
+        async move { result.await.unwrap_or(Err(ReadError::LeadershipLost)) }
+    }
+
+    /// (Leader only) Like [`read_index`](Node::read_index), but resolves
+    /// straight away while this node holds a lease (see
+    /// [`NodeBuilder::lease_reads`]), without waiting for a round of
+    /// heartbeats. Falls back to `read_index` otherwise.
+    pub fn read_with_lease(self: &Arc<Self>) -> impl Future<Output = Result<u32, ReadError>> {
+        let (done, result) = oneshot::channel();
+        let mut state = self.state.lock().expect("poisoned!");
+        self.start_lease_read(&mut state, done);
+        async move { result.await.unwrap_or(Err(ReadError::LeadershipLost)) }
+    }
+
+    /// (Leader only) Hand leadership over to `to`, or to the most up-to-date
+    /// peer if `None`, e.g. before this tab closes. The target is brought up
+    /// to date and then told to start an election immediately. `issue` is
+    /// ignored while the transfer is in progress, and the transfer is
+    /// abandoned if it hasn't completed within an election timeout.
+    pub fn transfer_leadership(self: &Arc<Self>, to: Option<Peer>) {
+        let mut state = self.state.lock().expect("poisoned!");
+        self.start_transfer(&mut state, to);
+    }
+
+    /// (Leader only) Change the set of voters. Resolves once the new
+    /// configuration is committed.
+    ///
+    /// How the cluster gets there depends on
+    /// [`NodeBuilder::membership_changes`]. With
+    /// [`MembershipChanges::Joint`] (the default), any number of voters can
+    /// change at once: the cluster first commits a configuration needing
+    /// majorities of both the old and new voters, and then the new
+    /// configuration alone. With [`MembershipChanges::SingleServer`], the new
+    /// configuration is committed directly, so it may only add or remove a
+    /// single voter, and anything more fails with
+    /// [`MembershipError::NotSingleChange`].
+    ///
+    /// Nodes joining or leaving the channel are added or removed
+    /// automatically, so this is only needed to override that. Voters removed
+    /// here stay removed (however often they're heard from) until they're
+    /// included in another call.
+    pub fn change_membership(
+        self: &Arc<Self>,
+        voters: HashSet<Peer>,
+    ) -> impl Future<Output = Result<(), MembershipError>> {
+        let (done, result) = oneshot::channel();
+        let mut state = self.state.lock().expect("poisoned!");
+        let Membership {
+            voters: current,
+            learners,
+            ..
+        } = state.membership();
+        let removed = current.difference(&voters).copied().collect();
+        self.start_membership_change(&mut state, voters, learners, removed, Some(done));
+        async move { result.await.unwrap_or(Err(MembershipError::LeadershipLost)) }
+    }
+
+    /// (Leader only) Make a learner a voter. Resolves once the new
+    /// configuration is committed.
+    pub fn promote_learner(
+        self: &Arc<Self>,
+        learner: Peer,
+    ) -> impl Future<Output = Result<(), MembershipError>> {
+        let (done, result) = oneshot::channel();
+        let mut state = self.state.lock().expect("poisoned!");
+        let Membership {
+            mut voters,
+            mut learners,
+            ..
+        } = state.membership();
+        if learners.remove(&learner) {
+            voters.insert(learner);
+            self.start_membership_change(&mut state, voters, learners, HashSet::new(), Some(done));
+        } else {
+            let _ = done.send(Err(MembershipError::NotLearner));
         }
+        async move { result.await.unwrap_or(Err(MembershipError::LeadershipLost)) }
     }
 
     pub(crate) fn call_on_received(&self, data: T) {
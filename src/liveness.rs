@@ -0,0 +1,240 @@
+use std::sync::Arc;
+
+use crate::{
+    raft::{Peer, Role},
+    rpc::{Message, Recipient},
+    Node, NodeState,
+};
+
+impl<T> Node<T>
+where
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
+{
+    /// (Leader) A follower answered, so it's alive
+    pub(crate) fn mark_seen(&self, state: &mut NodeState<T>, follower: Peer) {
+        state.last_seen.insert(follower, js_sys::Date::now());
+        state.suspects.remove(&follower);
+    }
+
+    /// (Leader) Run with each quorum check. Peers that haven't answered for
+    /// `suspect_after_ms` are suspected, and those silent for
+    /// `evict_after_ms` are removed from the configuration, just as if they
+    /// had left the channel. Tabs that crash, or are frozen or killed by the
+    /// browser, never say goodbye.
+    pub(crate) fn detect_failures(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let evicted = state.find_failures(
+            self.peer(),
+            js_sys::Date::now(),
+            self.suspect_after_ms,
+            self.evict_after_ms,
+        );
+        if evicted.is_empty() {
+            return;
+        }
+        for peer in evicted {
+            state.evict_peer(peer);
+        }
+        self.send(Message::PeerSet(state.peers.clone()), Recipient::Everyone);
+        self.reconcile_membership(state);
+    }
+
+    /// (Leader) Hearing from a peer we'd forgotten about (e.g. a tab that was
+    /// evicted while frozen) means it's back, so add it again, as a learner if
+    /// that's what it was
+    pub(crate) fn readmit_peer(self: Arc<Self>, peer: Peer) {
+        let state = self.state.lock().expect("poisoned mutex!");
+        if state.role != Role::Leader || state.peers.contains(&peer) || state.is_removed(peer) {
+            return;
+        }
+
+        let learner = state.evicted_learners.contains(&peer);
+        drop(state);
+        self.add_peer(peer, learner);
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// (Leader) Suspect the peers (other than `me`) that haven't answered for
+    /// `suspect_after_ms` as of `now`, in ms since the epoch. Returns those
+    /// silent for `evict_after_ms` (unless that's 0), to be evicted.
+    pub(crate) fn find_failures(
+        &mut self,
+        me: Peer,
+        now: f64,
+        suspect_after_ms: u32,
+        evict_after_ms: u32,
+    ) -> Vec<Peer> {
+        let mut evicted = Vec::new();
+        for peer in self.replication_targets() {
+            if peer == me {
+                continue;
+            }
+
+            // Peers we haven't heard from since being elected get a full
+            // timeout's grace
+            let silent_ms = now - *self.last_seen.entry(peer).or_insert(now);
+            if evict_after_ms > 0 && silent_ms >= f64::from(evict_after_ms) {
+                evicted.push(peer);
+            } else if silent_ms >= f64::from(suspect_after_ms) {
+                self.suspects.insert(peer);
+            }
+        }
+        evicted
+    }
+}
+
+impl<T> NodeState<T> {
+    /// Whether `peer` was removed from the configuration on purpose, so
+    /// isn't added back when it's heard from
+    pub(crate) fn is_removed(&self, peer: Peer) -> bool {
+        self.membership
+            .as_ref()
+            .is_some_and(|membership| membership.removed.contains(&peer))
+    }
+
+    /// What a node that isn't a voter (`me`) sends when its election timer
+    /// fires: a reminder that it's here, in case it was evicted while
+    /// unresponsive. Nothing, if it was removed on purpose.
+    pub(crate) fn rejoin_message(&self, me: Peer) -> Option<Message<T>> {
+        if self.is_removed(me) {
+            None
+        } else if self.learners.contains(&me) {
+            Some(Message::LearnerAdded)
+        } else {
+            Some(Message::PeerAdded)
+        }
+    }
+
+    /// Start tracking a peer that joined (or came back), so the leader adds
+    /// it to the configuration (unless it was removed on purpose)
+    pub(crate) fn admit_peer(&mut self, peer: Peer, learner: bool) {
+        self.peers.insert(peer);
+        if self.is_removed(peer) {
+            return;
+        }
+        if learner {
+            self.learners.insert(peer);
+        }
+        self.evicted_learners.remove(&peer);
+        self.leaving.remove(&peer);
+        self.joining.insert(peer);
+    }
+
+    /// Forget a peer that stopped responding, remembering whether it was a
+    /// learner in case it comes back
+    pub(crate) fn evict_peer(&mut self, peer: Peer) {
+        if self.learners.contains(&peer) {
+            self.evicted_learners.insert(peer);
+        }
+        self.forget_peer(peer);
+    }
+
+    /// Stop tracking a peer that left (or was evicted), so the leader removes
+    /// it from the configuration
+    pub(crate) fn forget_peer(&mut self, peer: Peer) {
+        self.peers.remove(&peer);
+        self.learners.remove(&peer);
+        self.joining.remove(&peer);
+        self.leaving.insert(peer);
+        self.suspects.remove(&peer);
+        self.last_seen.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Membership;
+
+    #[test]
+    fn evicted_learners_are_readmitted_as_learners() {
+        let mut state = NodeState::<()>::default();
+        let (voter, learner) = (Peer::from(1), Peer::from(2));
+        state.admit_peer(voter, false);
+        state.admit_peer(learner, true);
+
+        state.evict_peer(voter);
+        state.evict_peer(learner);
+        assert!(state.peers.is_empty());
+        assert!(state.learners.is_empty());
+        assert!(state.leaving.contains(&voter) && state.leaving.contains(&learner));
+
+        for peer in [voter, learner].iter() {
+            let was_learner = state.evicted_learners.contains(peer);
+            state.admit_peer(*peer, was_learner);
+        }
+        assert!(!state.learners.contains(&voter));
+        assert!(state.learners.contains(&learner));
+        assert!(state.evicted_learners.is_empty());
+        assert!(state.leaving.is_empty());
+        assert_eq!(state.joining.len(), 2);
+    }
+
+    #[test]
+    fn silent_peers_are_suspected_then_evicted() {
+        let voters = (1..=5).map(Peer::from).collect();
+        let mut state = NodeState::<()> {
+            membership: Some(Membership::new(voters)),
+            ..NodeState::default()
+        };
+        let now = 20_000.0;
+        state.last_seen.insert(Peer::from(2), now - 999.0);
+        state.last_seen.insert(Peer::from(3), now - 1000.0);
+        state.last_seen.insert(Peer::from(4), now - 9999.0);
+        state.last_seen.insert(Peer::from(5), now - 10_000.0);
+
+        let evicted = state.find_failures(Peer::from(1), now, 1000, 10_000);
+        assert_eq!(evicted, vec![Peer::from(5)]);
+        let suspects = [3, 4].iter().map(|id| Peer::from(*id)).collect();
+        assert_eq!(state.suspects, suspects);
+
+        // Never evicted with eviction off, just suspected
+        state.suspects.clear();
+        assert!(state.find_failures(Peer::from(1), now, 1000, 0).is_empty());
+        assert!(state.suspects.contains(&Peer::from(5)));
+    }
+
+    #[test]
+    fn unseen_peers_get_a_full_timeout() {
+        let mut state = NodeState::<()> {
+            membership: Some(Membership::new(
+                [1, 2].iter().map(|id| Peer::from(*id)).collect(),
+            )),
+            ..NodeState::default()
+        };
+        assert!(state
+            .find_failures(Peer::from(1), 5000.0, 1000, 2000)
+            .is_empty());
+        assert!(state.suspects.is_empty());
+        assert!(!state.last_seen.contains_key(&Peer::from(1)));
+
+        let evicted = state.find_failures(Peer::from(1), 7000.0, 1000, 2000);
+        assert_eq!(evicted, vec![Peer::from(2)]);
+    }
+
+    #[test]
+    fn removed_peers_stay_removed() {
+        let removed = Peer::from(3);
+        let mut membership = Membership::new([1, 2].iter().map(|id| Peer::from(*id)).collect());
+        membership.removed.insert(removed);
+        let mut state = NodeState::<()> {
+            membership: Some(membership),
+            ..NodeState::default()
+        };
+
+        // However many election timeouts pass, the removed node doesn't ask
+        // to be added back, and the leader wouldn't add it anyway
+        for _ in 0..3 {
+            assert!(state.rejoin_message(removed).is_none());
+            state.admit_peer(removed, false);
+            assert!(state.joining.is_empty());
+        }
+        assert!(matches!(
+            state.rejoin_message(Peer::from(4)),
+            Some(Message::PeerAdded)
+        ));
+    }
+}
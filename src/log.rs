@@ -0,0 +1,401 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{membership::Membership, session::Sessions};
+
+/// A single entry in the replicated log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry<T> {
+    pub term: u32,
+    pub index: u32,
+    pub payload: EntryPayload<T>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EntryPayload<T> {
+    /// An empty entry, appended by a new leader so that entries from previous
+    /// terms can be committed
+    Blank,
+    /// Application data issued through the leader
+    Normal(T),
+    /// Application data proposed by a client session. Applied at most once
+    /// per `(session, seq)`, however many times it is appended. The client
+    /// has settled every proposal before `settled_below`, so those are never
+    /// applied once this entry has been.
+    Session {
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+        payload: T,
+    },
+    /// A new cluster configuration, which takes effect as soon as it is
+    /// appended (before it is committed)
+    Membership(Membership),
+}
+
+/// A compacted prefix of the log, captured from the state machine after
+/// applying every entry up to and including `index`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u32,
+    pub term: u32,
+    pub data: serde_json::Value,
+    /// The configuration in effect at `index`
+    #[serde(default)]
+    pub membership: Option<Membership>,
+    /// Client sessions as of `index`
+    #[serde(default)]
+    pub sessions: Sessions,
+}
+
+/// The node's copy of the replicated log. Indices start at 1, with index 0
+/// (term 0) standing in for the empty log. Once compacted, entries up to
+/// `compacted_index` are discarded and only live on in a snapshot.
+pub(crate) struct Log<T> {
+    entries: Vec<Entry<T>>,
+    compacted_index: u32,
+    compacted_term: u32,
+}
+
+impl<T> Default for Log<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            compacted_index: 0,
+            compacted_term: 0,
+        }
+    }
+}
+
+impl<T> Log<T>
+where
+    T: Clone,
+{
+    /// Rebuild a log from persisted entries following the compacted point
+    pub(crate) fn restore(
+        compacted_index: u32,
+        compacted_term: u32,
+        entries: Vec<Entry<T>>,
+    ) -> Self {
+        let mut log = Self {
+            entries: Vec::new(),
+            compacted_index,
+            compacted_term,
+        };
+        log.append_entries(entries);
+        log
+    }
+
+    pub(crate) fn last_index(&self) -> u32 {
+        self.entries
+            .last()
+            .map(|entry| entry.index)
+            .unwrap_or(self.compacted_index)
+    }
+
+    /// Index of the last entry discarded by compaction
+    pub(crate) fn compacted_index(&self) -> u32 {
+        self.compacted_index
+    }
+
+    /// Term of the last entry (or of the snapshot, if the log is empty)
+    pub(crate) fn last_term(&self) -> u32 {
+        self.entries
+            .last()
+            .map(|entry| entry.term)
+            .unwrap_or(self.compacted_term)
+    }
+
+    /// Whether a log ending at `last_index` in `last_term` is at least as up
+    /// to date as this one: a later last term wins, and with the same last
+    /// term, the longer log does
+    pub(crate) fn is_up_to_date(&self, last_index: u32, last_term: u32) -> bool {
+        (last_term, last_index) >= (self.last_term(), self.last_index())
+    }
+
+    /// Get the term of the entry at `index`, if it exists (and hasn't been
+    /// compacted)
+    pub(crate) fn term_at(&self, index: u32) -> Option<u32> {
+        if index == self.compacted_index {
+            Some(self.compacted_term)
+        } else {
+            self.get(index).map(|entry| entry.term)
+        }
+    }
+
+    pub(crate) fn get(&self, index: u32) -> Option<&Entry<T>> {
+        if index <= self.compacted_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.compacted_index) as usize - 1)
+    }
+
+    /// Clone all entries starting at (and including) `index`
+    pub(crate) fn entries_from(&self, index: u32) -> Vec<Entry<T>> {
+        let start = index.saturating_sub(self.compacted_index).max(1) as usize - 1;
+        self.entries
+            .get(start..)
+            .map(|entries| entries.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// At most `max` entries, starting from `index`
+    pub(crate) fn entries_between(&self, index: u32, max: u32) -> Vec<Entry<T>> {
+        let start = index.saturating_sub(self.compacted_index).max(1) as usize - 1;
+        let end = start.saturating_add(max as usize).min(self.entries.len());
+        self.entries
+            .get(start..end)
+            .map(|entries| entries.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Whether this log contains an entry at `index` with the given `term`.
+    /// Compacted entries were committed, so they always match.
+    pub(crate) fn matches(&self, index: u32, term: u32) -> bool {
+        index < self.compacted_index || self.term_at(index) == Some(term)
+    }
+
+    /// Index of the last entry with the given `term`, if there is one
+    pub(crate) fn last_index_of_term(&self, term: u32) -> Option<u32> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.term == term)
+            .map(|entry| entry.index)
+    }
+
+    /// (Follower) When the entry at `prev_log_index` doesn't match the leader's,
+    /// find where the leader should retry from. Returns the conflicting term
+    /// (if there is an entry at `prev_log_index`) and the first index with that
+    /// term, or just past the end of the log when the entry is missing.
+    pub(crate) fn conflict_hint(&self, prev_log_index: u32) -> (u32, Option<u32>) {
+        match self.term_at(prev_log_index) {
+            None => (self.last_index() + 1, None),
+            Some(term) => {
+                let mut index = prev_log_index;
+                while index > self.compacted_index + 1 && self.term_at(index - 1) == Some(term) {
+                    index -= 1;
+                }
+                (index, Some(term))
+            }
+        }
+    }
+
+    /// (Leader) Use a follower's conflict hint to pick the next index to send
+    /// it. If the leader has entries from the conflicting term, it resumes
+    /// just past the last of them; otherwise it skips the whole term.
+    pub(crate) fn next_index_after_conflict(
+        &self,
+        conflict_index: u32,
+        conflict_term: Option<u32>,
+    ) -> u32 {
+        conflict_term
+            .and_then(|term| self.last_index_of_term(term))
+            .map(|index| index + 1)
+            .unwrap_or(conflict_index)
+    }
+
+    /// The latest configuration entry at or before `index`, and its index
+    pub(crate) fn membership_at(&self, index: u32) -> Option<(u32, &Membership)> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match entry.payload {
+                EntryPayload::Membership(ref membership) => Some((entry.index, membership)),
+                _ => None,
+            })
+    }
+
+    /// Append a new entry to the end of the log (used by the leader)
+    pub(crate) fn append(&mut self, term: u32, payload: EntryPayload<T>) -> u32 {
+        let index = self.last_index() + 1;
+        self.entries.push(Entry {
+            term,
+            index,
+            payload,
+        });
+        index
+    }
+
+    /// Append entries received from the leader. Entries that are already
+    /// present are skipped, and an existing entry that conflicts with a new
+    /// one (same index, different term) is deleted along with everything that
+    /// follows it. Returns the index of the first entry that was written, if
+    /// any were.
+    pub(crate) fn append_entries(&mut self, entries: Vec<Entry<T>>) -> Option<u32> {
+        let mut first_written = None;
+        for entry in entries {
+            if entry.index <= self.compacted_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self
+                    .entries
+                    .truncate((entry.index - self.compacted_index) as usize - 1),
+                None => {}
+            }
+            if entry.index == self.last_index() + 1 {
+                first_written = first_written.or(Some(entry.index));
+                self.entries.push(entry);
+            }
+        }
+        first_written
+    }
+
+    /// Discard every entry up to and including `index`
+    pub(crate) fn compact(&mut self, index: u32) {
+        if let Some(term) = self.term_at(index) {
+            if index > self.compacted_index {
+                self.entries
+                    .drain(..(index - self.compacted_index) as usize);
+                self.compacted_index = index;
+                self.compacted_term = term;
+            }
+        }
+    }
+
+    /// Reset the log to start after an installed snapshot. Entries following
+    /// the snapshot are kept if the log agrees with it, otherwise the whole
+    /// log is discarded. Returns whether the following entries were kept.
+    pub(crate) fn install_snapshot(&mut self, index: u32, term: u32) -> bool {
+        if index > self.compacted_index && self.term_at(index) == Some(term) {
+            self.compact(index);
+            true
+        } else {
+            self.entries.clear();
+            self.compacted_index = index;
+            self.compacted_term = term;
+            false
+        }
+    }
+}
+
+/// Find the highest index that has been replicated on a majority of `voters`
+/// nodes, given each node's matched index.
+pub(crate) fn quorum_index<I>(matched: I, voters: usize) -> u32
+where
+    I: IntoIterator<Item = u32>,
+{
+    let mut matched: Vec<u32> = matched.into_iter().collect();
+    matched.resize(voters.max(matched.len()), 0);
+    matched.sort_unstable_by(|a, b| b.cmp(a));
+    matched.get(voters / 2).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u32, index: u32) -> Entry<u32> {
+        Entry {
+            term,
+            index,
+            payload: EntryPayload::Normal(index),
+        }
+    }
+
+    #[test]
+    fn append_assigns_indices() {
+        let mut log = Log::default();
+        assert_eq!(log.last_index(), 0);
+        assert_eq!(log.append(1, EntryPayload::Blank), 1);
+        assert_eq!(log.append(1, EntryPayload::Normal(7)), 2);
+        assert_eq!(log.entries_from(2).len(), 1);
+        assert_eq!(log.get(2).unwrap().payload, EntryPayload::Normal(7));
+        assert!(log.matches(0, 0));
+        assert!(log.matches(2, 1));
+        assert!(!log.matches(3, 1));
+    }
+
+    #[test]
+    fn append_entries_truncates_conflicts() {
+        let mut log = Log::default();
+        assert_eq!(
+            log.append_entries(vec![entry(1, 1), entry(1, 2), entry(1, 3)]),
+            Some(1)
+        );
+        assert_eq!(log.append_entries(vec![entry(1, 2), entry(2, 3)]), Some(3));
+        assert_eq!(log.last_index(), 3);
+        assert_eq!(log.term_at(3), Some(2));
+
+        // Out of order and duplicate entries are ignored
+        assert_eq!(log.append_entries(vec![entry(2, 5)]), None);
+        assert_eq!(log.append_entries(vec![entry(1, 1)]), None);
+        assert_eq!(log.last_index(), 3);
+    }
+
+    #[test]
+    fn conflict_hints_skip_terms() {
+        let mut follower = Log::default();
+        follower.append_entries(vec![entry(1, 1), entry(2, 2), entry(2, 3), entry(2, 4)]);
+
+        // Missing entry
+        assert_eq!(follower.conflict_hint(6), (5, None));
+        // Conflicting entry: back up to the start of term 2
+        assert_eq!(follower.conflict_hint(4), (2, Some(2)));
+
+        let mut leader = Log::default();
+        leader.append_entries(vec![entry(1, 1), entry(3, 2), entry(3, 3)]);
+        assert_eq!(leader.next_index_after_conflict(2, Some(2)), 2);
+        assert_eq!(leader.next_index_after_conflict(2, Some(1)), 2);
+        assert_eq!(leader.next_index_after_conflict(5, None), 5);
+    }
+
+    #[test]
+    fn compaction_keeps_indices() {
+        let mut log = Log::default();
+        log.append_entries(vec![entry(1, 1), entry(1, 2), entry(2, 3), entry(2, 4)]);
+        log.compact(2);
+
+        assert_eq!(log.compacted_index(), 2);
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.term_at(2), Some(1));
+        assert_eq!(log.term_at(1), None);
+        assert!(log.get(2).is_none());
+        assert_eq!(log.get(3), Some(&entry(2, 3)));
+        assert_eq!(log.entries_from(1), vec![entry(2, 3), entry(2, 4)]);
+        assert!(log.matches(1, 5));
+
+        log.append_entries(vec![entry(1, 2), entry(3, 4)]);
+        assert_eq!(log.entries_from(3), vec![entry(2, 3), entry(3, 4)]);
+        assert_eq!(log.conflict_hint(4), (4, Some(3)));
+        assert_eq!(log.conflict_hint(3), (3, Some(2)));
+    }
+
+    #[test]
+    fn install_snapshot_keeps_matching_suffix() {
+        let mut log = Log::default();
+        log.append_entries(vec![entry(1, 1), entry(1, 2), entry(2, 3)]);
+
+        assert!(log.install_snapshot(2, 1));
+        assert_eq!(log.entries_from(1), vec![entry(2, 3)]);
+
+        assert!(!log.install_snapshot(5, 3));
+        assert_eq!(log.last_index(), 5);
+        assert_eq!(log.term_at(5), Some(3));
+        assert!(log.entries_from(1).is_empty());
+    }
+
+    #[test]
+    fn quorum_index_is_majority_match() {
+        assert_eq!(quorum_index(vec![5], 1), 5);
+        assert_eq!(quorum_index(vec![5, 3, 1], 3), 3);
+        assert_eq!(quorum_index(vec![5, 3], 4), 0);
+        assert_eq!(quorum_index(vec![5, 4, 3], 4), 3);
+    }
+
+    #[test]
+    fn up_to_date_compares_last_term_then_index() {
+        let mut log = Log::default();
+        assert!(log.is_up_to_date(0, 0));
+        log.append_entries(vec![entry(1, 1), entry(2, 2)]);
+        assert_eq!(log.last_term(), 2);
+
+        assert!(log.is_up_to_date(2, 2));
+        assert!(log.is_up_to_date(1, 3));
+        assert!(!log.is_up_to_date(1, 2));
+        assert!(!log.is_up_to_date(5, 1));
+    }
+}
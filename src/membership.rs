@@ -0,0 +1,731 @@
+use futures_channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    log::{quorum_index, EntryPayload},
+    raft::{Peer, Role},
+    Node, NodeState,
+};
+
+/// A cluster configuration: the peers whose votes count. While membership is
+/// changing, the cluster passes through a joint configuration, where every
+/// decision needs a majority of both the old and the new voters.
+///
+/// With a tie-breaker, exactly half of an even number of voters is also a
+/// quorum, as long as it includes the tie-breaker. Any two quorums of the
+/// same configuration still overlap, which lets the tie-breaker carry on
+/// alone in a two-node cluster. Quorums of configurations one voter apart
+/// needn't overlap, though, so a configuration with a tie-breaker is only
+/// ever entered or left through a joint configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    pub voters: HashSet<Peer>,
+    /// The new voters, while in a joint configuration
+    pub joint: Option<HashSet<Peer>>,
+    /// Peers that are sent the log, but never vote or count towards quorum
+    #[serde(default)]
+    pub learners: HashSet<Peer>,
+    /// Breaks ties between `voters`, if anyone does
+    #[serde(default)]
+    pub tie_breaker: Option<Peer>,
+    /// Breaks ties between the new voters, while in a joint configuration
+    #[serde(default)]
+    pub joint_tie_breaker: Option<Peer>,
+    /// Peers removed through [`Node::change_membership`], which aren't added
+    /// back when they're heard from again (only by another explicit change)
+    #[serde(default)]
+    pub removed: HashSet<Peer>,
+}
+
+impl Membership {
+    pub fn new(voters: HashSet<Peer>) -> Self {
+        Self {
+            voters,
+            joint: None,
+            learners: HashSet::new(),
+            tie_breaker: None,
+            joint_tie_breaker: None,
+            removed: HashSet::new(),
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.joint.is_some()
+    }
+
+    /// The voters this configuration is moving to (or the current voters, if
+    /// it isn't joint)
+    pub fn target(&self) -> &HashSet<Peer> {
+        self.joint.as_ref().unwrap_or(&self.voters)
+    }
+
+    /// Whether `peer` is a voter in either configuration
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.voters.contains(peer) || self.target().contains(peer)
+    }
+
+    /// Every voter in either configuration, and every learner
+    pub fn peers(&self) -> HashSet<Peer> {
+        self.voters
+            .iter()
+            .chain(self.target())
+            .chain(&self.learners)
+            .copied()
+            .collect()
+    }
+
+    /// The tie-breaker for the voters this configuration is moving to
+    pub fn target_tie_breaker(&self) -> Option<Peer> {
+        if self.is_joint() {
+            self.joint_tie_breaker
+        } else {
+            self.tie_breaker
+        }
+    }
+
+    /// Whether `granted` holds a majority of the voters (of both
+    /// configurations, when joint)
+    pub(crate) fn is_quorum(&self, granted: &HashSet<Peer>) -> bool {
+        let majority = |voters: &HashSet<Peer>, tie_breaker: Option<Peer>| {
+            let count = voters.iter().filter(|peer| granted.contains(peer)).count();
+            count * 2 > voters.len()
+                || (count * 2 == voters.len()
+                    && breaks_tie(voters, tie_breaker).is_some_and(|peer| granted.contains(&peer)))
+        };
+        majority(&self.voters, self.tie_breaker)
+            && majority(self.target(), self.target_tie_breaker())
+    }
+
+    /// The highest index replicated on a majority of the voters (of both
+    /// configurations, when joint)
+    pub(crate) fn quorum_index(&self, match_index: &HashMap<Peer, u32>) -> u32 {
+        let matched = |peer: &Peer| match_index.get(peer).copied().unwrap_or(0);
+        let quorum = |voters: &HashSet<Peer>, tie_breaker: Option<Peer>| {
+            let majority = quorum_index(voters.iter().map(matched), voters.len());
+            match breaks_tie(voters, tie_breaker) {
+                // Or whatever half the voters (including the tie-breaker) have
+                Some(peer) => majority.max(
+                    quorum_index(voters.iter().map(matched), voters.len() - 1).min(matched(&peer)),
+                ),
+                None => majority,
+            }
+        };
+        quorum(&self.voters, self.tie_breaker).min(quorum(self.target(), self.target_tie_breaker()))
+    }
+
+    /// The configuration to move to from this one (which isn't joint), to
+    /// end up with `voters` and `learners`, and `tie_breaker` breaking ties
+    /// between the voters if there can be any. Voters change through a joint
+    /// configuration, unless `single_server` is set and no tie-breaker is
+    /// involved on either side.
+    pub(crate) fn next(
+        &self,
+        voters: HashSet<Peer>,
+        learners: HashSet<Peer>,
+        single_server: bool,
+        tie_breaker: Option<Peer>,
+    ) -> Membership {
+        let tie_breaker = breaks_tie(&voters, tie_breaker);
+
+        // Learners don't affect quorum, so they can change straight away
+        if voters == self.voters && tie_breaker == self.tie_breaker {
+            return Membership {
+                learners,
+                ..self.clone()
+            };
+        }
+
+        if single_server && self.tie_breaker.is_none() && tie_breaker.is_none() {
+            Membership {
+                learners,
+                removed: self.removed.clone(),
+                ..Membership::new(voters)
+            }
+        } else {
+            Membership {
+                voters: self.voters.clone(),
+                joint: Some(voters),
+                learners,
+                tie_breaker: self.tie_breaker,
+                joint_tie_breaker: tie_breaker,
+                removed: self.removed.clone(),
+            }
+        }
+    }
+
+    /// This configuration, with `removed` kept out of it from now on. Peers
+    /// it (explicitly) adds back are no longer kept out.
+    pub(crate) fn removing(mut self, removed: HashSet<Peer>) -> Membership {
+        self.removed.extend(removed);
+        let kept = self
+            .target()
+            .union(&self.learners)
+            .copied()
+            .collect::<HashSet<_>>();
+        self.removed.retain(|peer| !kept.contains(peer));
+        self
+    }
+
+    /// The new configuration on its own, to move to once this joint one is
+    /// committed
+    pub(crate) fn leave_joint(self) -> Membership {
+        Membership {
+            voters: self.joint.unwrap_or(self.voters),
+            joint: None,
+            learners: self.learners,
+            tie_breaker: self.joint_tie_breaker,
+            joint_tie_breaker: None,
+            removed: self.removed,
+        }
+    }
+}
+
+/// The tie-breaker's peer, if it can settle a split between `voters` (there
+/// are an even number of them, and it's one)
+fn breaks_tie(voters: &HashSet<Peer>, tie_breaker: Option<Peer>) -> Option<Peer> {
+    tie_breaker.filter(|peer| voters.len() & 1 == 0 && voters.contains(peer))
+}
+
+/// How the configuration moves from one set of voters to another
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MembershipChanges {
+    /// Change any number of voters at once, through a joint configuration
+    #[default]
+    Joint,
+    /// Add or remove a single voter per configuration entry. Any two
+    /// configurations that differ by one voter share a majority, so no joint
+    /// configuration is needed. Can't be combined with a tie-breaker (see
+    /// [`NodeBuilder::tie_breaker`](crate::NodeBuilder::tie_breaker)).
+    SingleServer,
+}
+
+/// Why a membership change couldn't be made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MembershipError {
+    /// Only the leader can change membership
+    NotLeader,
+    /// Another change hasn't finished yet
+    ChangeInProgress,
+    /// The new configuration has no voters
+    Empty,
+    /// In single-server mode, only one voter can be added or removed at once
+    NotSingleChange,
+    /// Only learners can be promoted to voters
+    NotLearner,
+    /// This node stopped being the leader before the change was committed
+    LeadershipLost,
+    /// The node was stopped before the change was committed
+    Stopped,
+}
+
+impl std::fmt::Display for MembershipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MembershipError::NotLeader => write!(f, "not the leader"),
+            MembershipError::ChangeInProgress => write!(f, "membership change in progress"),
+            MembershipError::Empty => write!(f, "membership can't be empty"),
+            MembershipError::NotSingleChange => write!(f, "can only change one voter at once"),
+            MembershipError::NotLearner => write!(f, "not a learner"),
+            MembershipError::LeadershipLost => write!(f, "leadership lost during change"),
+            MembershipError::Stopped => write!(f, "node stopped"),
+        }
+    }
+}
+
+impl std::error::Error for MembershipError {}
+
+pub(crate) type MembershipDone = oneshot::Sender<Result<(), MembershipError>>;
+
+impl<T> Node<T>
+where
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
+{
+    /// (Leader) Start moving to a configuration with the given voters and
+    /// learners, by appending the joint configuration (or the new
+    /// configuration, in single-server mode). Peers in `removed` are being
+    /// removed on purpose, so they're kept out until explicitly added back.
+    /// `done` is resolved once the new configuration is committed (or
+    /// straight away, if the change can't be made).
+    pub(crate) fn start_membership_change(
+        self: &Arc<Self>,
+        state: &mut NodeState<T>,
+        voters: HashSet<Peer>,
+        learners: HashSet<Peer>,
+        removed: HashSet<Peer>,
+        done: Option<MembershipDone>,
+    ) {
+        let membership = state.membership();
+        let learners = learners.difference(&voters).copied().collect();
+        // A tie-breaker (from a leader configured differently) rules out
+        // single-server changes
+        let single_server = self.membership_changes == MembershipChanges::SingleServer
+            && membership.tie_breaker.is_none();
+        let next = membership
+            .next(
+                voters.clone(),
+                learners,
+                single_server,
+                self.tie_breaker_for(),
+            )
+            .removing(removed);
+        let result = if state.role != Role::Leader {
+            Err(MembershipError::NotLeader)
+        } else if !state.can_change_membership() {
+            Err(MembershipError::ChangeInProgress)
+        } else if voters.is_empty() {
+            Err(MembershipError::Empty)
+        } else if next == membership {
+            Ok(())
+        } else if single_server && membership.voters.symmetric_difference(&voters).count() > 1 {
+            Err(MembershipError::NotSingleChange)
+        } else {
+            state.membership_change = done;
+            state.append_to_log(EntryPayload::Membership(next));
+            self.replicate(state);
+            return;
+        };
+
+        if let Some(done) = done {
+            let _ = done.send(result);
+        }
+    }
+
+    /// (Leader) Called when the commit index advances. Once the joint
+    /// configuration is committed, move on to the new one, and once that is
+    /// committed, the change is done.
+    pub(crate) fn advance_membership(self: &Arc<Self>, state: &mut NodeState<T>) {
+        if state.role != Role::Leader || state.membership_index > state.commit_index {
+            return;
+        }
+
+        let membership = state.membership();
+        if membership.is_joint() {
+            state.append_to_log(EntryPayload::Membership(membership.leave_joint()));
+            self.replicate(state);
+            return;
+        }
+
+        if let Some(done) = state.membership_change.take() {
+            let _ = done.send(Ok(()));
+        }
+
+        // A leader that removed itself hands over to the remaining voters
+        if !membership.voters.contains(&self.peer()) {
+            self.become_follower(state);
+            self.update_learner_role(state);
+            return;
+        }
+
+        self.reconcile_membership(state);
+    }
+
+    /// (Leader) Add peers that have joined the channel as voters (or
+    /// learners), and remove those that have left, once no other change is in
+    /// progress. Joining voters are replicated to as non-voters until they've
+    /// caught up, so they don't hold up commits as soon as they're added.
+    pub(crate) fn reconcile_membership(self: &Arc<Self>, state: &mut NodeState<T>) {
+        if state.role != Role::Leader || !state.can_change_membership() {
+            return;
+        }
+
+        if let Some((voters, learners)) = state.reconciled_membership(self.membership_changes) {
+            self.start_membership_change(state, voters, learners, HashSet::new(), None);
+        }
+    }
+
+    /// (Leader) With the tie-breaker policy enabled, we name ourselves as the
+    /// tie-breaker of each configuration we move to. The leader is the voter
+    /// known to be up to date and reachable, so the designation follows
+    /// leadership, through a membership change after each election.
+    fn tie_breaker_for(&self) -> Option<Peer> {
+        if self.tie_breaker {
+            Some(self.peer())
+        } else {
+            None
+        }
+    }
+
+    /// Keep this node's role in line with the configuration, as it is
+    /// promoted from (or demoted to) a learner
+    pub(crate) fn update_learner_role(&self, state: &mut NodeState<T>) {
+        let me = self.peer();
+        let learner = match state.membership {
+            Some(ref membership) => {
+                membership.learners.contains(&me)
+                    || (state.learners.contains(&me) && !membership.contains(&me))
+            }
+            None => state.learners.contains(&me),
+        };
+
+        let role = match (state.role, learner) {
+            (Role::Learner, false) => Role::Follower,
+            (Role::Follower, true) => Role::Learner,
+            _ => return,
+        };
+        self.set_role(state, role);
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// The configuration in effect. Until this node has bootstrapped a
+    /// cluster (or been added to one), it has no voters at all.
+    pub(crate) fn membership(&self) -> Membership {
+        self.membership.clone().unwrap_or_default()
+    }
+
+    /// (Leader) The voters and learners to move to, adding peers that have
+    /// joined the channel and removing those that have left. Joining voters
+    /// are only added once they've caught up, and in single-server mode only
+    /// one voter changes at a time. `None` if no voters would be left.
+    pub(crate) fn reconciled_membership(
+        &mut self,
+        changes: MembershipChanges,
+    ) -> Option<(HashSet<Peer>, HashSet<Peer>)> {
+        let Membership {
+            mut voters,
+            mut learners,
+            ..
+        } = self.membership();
+        let NodeState {
+            ref mut joining,
+            ref mut leaving,
+            learners: ref joined_as_learners,
+            ref match_index,
+            commit_index,
+            ..
+        } = *self;
+        joining.retain(|peer| !voters.contains(peer) && !learners.contains(peer));
+        leaving.retain(|peer| voters.contains(peer) || learners.contains(peer));
+
+        // Learners don't count towards quorum, so don't need to catch up
+        // first (or leave one at a time)
+        learners.extend(joining.intersection(joined_as_learners));
+        joining.retain(|peer| !learners.contains(peer));
+        for peer in leaving.iter() {
+            learners.remove(peer);
+        }
+        leaving.retain(|peer| voters.contains(peer));
+
+        let caught_up = joining
+            .iter()
+            .copied()
+            .filter(|peer| match_index.get(peer).copied().unwrap_or(0) >= commit_index)
+            .collect::<Vec<_>>();
+        match changes {
+            MembershipChanges::Joint => {
+                for peer in caught_up {
+                    joining.remove(&peer);
+                    voters.insert(peer);
+                }
+                for peer in leaving.drain() {
+                    voters.remove(&peer);
+                }
+            }
+
+            // Removals first, so leaving peers stop counting towards quorum
+            MembershipChanges::SingleServer => {
+                if let Some(peer) = leaving.iter().next().copied() {
+                    leaving.remove(&peer);
+                    voters.remove(&peer);
+                } else if let Some(peer) = caught_up.first() {
+                    joining.remove(peer);
+                    voters.insert(*peer);
+                }
+            }
+        }
+
+        if voters.is_empty() {
+            None
+        } else {
+            Some((voters, learners))
+        }
+    }
+
+    /// Fail the membership change in progress, if there is one
+    pub(crate) fn fail_membership_change(&mut self, error: MembershipError) {
+        if let Some(done) = self.membership_change.take() {
+            let _ = done.send(Err(error));
+        }
+    }
+
+    /// Start a new cluster with `peer` as its only voter, by committing the
+    /// initial configuration in term 1. Every other node joins through a
+    /// membership change, so there's only ever one initial configuration.
+    pub(crate) fn bootstrap(&mut self, peer: Peer) {
+        let mut voters = HashSet::new();
+        voters.insert(peer);
+        self.term = 1;
+        self.save_hard_state();
+        let index = self.append_to_log(EntryPayload::Membership(Membership::new(voters)));
+        self.commit_index = index;
+        self.last_applied = index;
+    }
+
+    /// Only one change can be in flight at once, and a new leader must commit
+    /// an entry from its own term before changing the configuration (so it
+    /// can't overwrite an uncommitted change from a previous leader).
+    fn can_change_membership(&self) -> bool {
+        !self.membership.as_ref().is_some_and(Membership::is_joint)
+            && self.membership_index <= self.commit_index
+            && self.membership_change.is_none()
+            && self.log.term_at(self.commit_index) == Some(self.term)
+    }
+
+    /// Peers that should be sent entries: every voter, and (on the leader)
+    /// joining peers that are catching up
+    pub(crate) fn replication_targets(&self) -> HashSet<Peer> {
+        let mut peers = self.membership().peers();
+        if self.role == Role::Leader {
+            peers.extend(self.joining.iter().filter(|peer| self.peers.contains(peer)));
+        }
+        peers
+    }
+
+    /// The configuration in effect at `index`, for a snapshot
+    pub(crate) fn membership_at(&self, index: u32) -> Option<Membership> {
+        self.log
+            .membership_at(index)
+            .map(|(_, membership)| membership.clone())
+            .or_else(|| {
+                self.snapshot
+                    .as_ref()
+                    .and_then(|snapshot| snapshot.membership.clone())
+            })
+    }
+
+    /// Find the latest configuration, after the log changes. Configurations
+    /// take effect as soon as they are in the log, even before they commit.
+    pub(crate) fn refresh_membership(&mut self) {
+        let (index, membership) = match self.log.membership_at(self.log.last_index()) {
+            Some((index, membership)) => (index, Some(membership.clone())),
+            None => match self.snapshot {
+                Some(ref snapshot) => (snapshot.index, snapshot.membership.clone()),
+                None => (0, None),
+            },
+        };
+        self.membership_index = index;
+        self.membership = membership;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(ids: &[u32]) -> HashSet<Peer> {
+        ids.iter().map(|id| Peer::from(*id)).collect()
+    }
+
+    #[test]
+    fn joint_quorum_needs_both_majorities() {
+        let membership = Membership {
+            voters: peers(&[1, 2, 3]),
+            joint: Some(peers(&[3, 4, 5])),
+            learners: HashSet::new(),
+            tie_breaker: None,
+            joint_tie_breaker: None,
+            removed: HashSet::new(),
+        };
+        assert!(!membership.is_quorum(&peers(&[1, 2])));
+        assert!(!membership.is_quorum(&peers(&[3, 4, 5])));
+        assert!(membership.is_quorum(&peers(&[2, 3, 4])));
+        assert_eq!(membership.peers(), peers(&[1, 2, 3, 4, 5]));
+
+        let match_index = [(1, 9), (2, 8), (3, 7), (4, 3), (5, 2)]
+            .iter()
+            .map(|(peer, index)| (Peer::from(*peer), *index))
+            .collect();
+        assert_eq!(membership.quorum_index(&match_index), 3);
+    }
+
+    #[test]
+    fn learners_never_count() {
+        let membership = Membership {
+            voters: peers(&[1, 2, 3]),
+            joint: None,
+            learners: peers(&[4, 5]),
+            tie_breaker: None,
+            joint_tie_breaker: None,
+            removed: HashSet::new(),
+        };
+        assert!(!membership.is_quorum(&peers(&[1, 4, 5])));
+        assert!(membership.is_quorum(&peers(&[1, 2])));
+        assert!(!membership.contains(&Peer::from(4)));
+        assert_eq!(membership.peers(), peers(&[1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn only_a_bootstrapped_node_has_voters() {
+        let mut state = NodeState::<u32> {
+            peers: peers(&[1, 2]),
+            ..NodeState::default()
+        };
+        assert!(state.membership().voters.is_empty());
+        assert!(!state.membership().is_quorum(&peers(&[1, 2])));
+
+        state.bootstrap(Peer::from(1));
+        assert_eq!(state.term, 1);
+        assert_eq!(state.commit_index, 1);
+        assert_eq!(state.membership().voters, peers(&[1]));
+        assert!(state.membership().is_quorum(&peers(&[1])));
+    }
+
+    fn tie_breaker(id: u32) -> Option<Peer> {
+        Some(Peer::from(id))
+    }
+
+    #[test]
+    fn stopping_fails_the_change_in_progress() {
+        let (done, mut result) = oneshot::channel();
+        let mut state = NodeState::<u32> {
+            membership_change: Some(done),
+            ..NodeState::default()
+        };
+        state.fail_membership_change(MembershipError::Stopped);
+        assert_eq!(result.try_recv(), Ok(Some(Err(MembershipError::Stopped))));
+        assert!(state.membership_change.is_none());
+    }
+
+    #[test]
+    fn changes_go_through_a_joint_configuration() {
+        let membership = Membership::new(peers(&[1, 2, 3]));
+        let next = membership.next(peers(&[3, 4, 5]), peers(&[1]), false, None);
+        assert_eq!(next.voters, peers(&[1, 2, 3]));
+        assert_eq!(next.target(), &peers(&[3, 4, 5]));
+        assert!(!next.is_quorum(&peers(&[3, 4, 5])));
+
+        let next = next.leave_joint();
+        assert_eq!(next.voters, peers(&[3, 4, 5]));
+        assert_eq!(next.learners, peers(&[1]));
+        assert!(next.is_quorum(&peers(&[3, 4])));
+    }
+
+    #[test]
+    fn joining_voters_wait_until_caught_up() {
+        let mut state = NodeState::<u32> {
+            joining: peers(&[2, 3, 4]),
+            learners: peers(&[4]),
+            ..NodeState::default()
+        };
+        state.bootstrap(Peer::from(1));
+        state.match_index.insert(Peer::from(2), 1);
+
+        let (voters, learners) = state
+            .reconciled_membership(MembershipChanges::Joint)
+            .unwrap();
+        assert_eq!(voters, peers(&[1, 2]));
+        assert_eq!(learners, peers(&[4]));
+        assert_eq!(state.joining, peers(&[3]));
+    }
+
+    #[test]
+    fn single_server_changes_remove_first_then_add_one_at_a_time() {
+        let mut state = NodeState::<u32> {
+            membership: Some(Membership::new(peers(&[1, 2, 3]))),
+            joining: peers(&[4, 5]),
+            leaving: peers(&[3]),
+            ..NodeState::default()
+        };
+        let changes = MembershipChanges::SingleServer;
+
+        let (voters, _) = state.reconciled_membership(changes).unwrap();
+        assert_eq!(voters, peers(&[1, 2]));
+        assert_eq!(state.joining.len(), 2);
+
+        state.membership = Some(Membership::new(voters));
+        let (voters, _) = state.reconciled_membership(changes).unwrap();
+        assert_eq!(voters.len(), 3);
+        assert_eq!(state.joining.len(), 1);
+    }
+
+    #[test]
+    fn tie_breaker_settles_even_splits() {
+        let mut membership = Membership::new(peers(&[1, 2]));
+        assert!(!membership.is_quorum(&peers(&[1])));
+
+        membership.tie_breaker = tie_breaker(1);
+        assert!(membership.is_quorum(&peers(&[1])));
+        assert!(!membership.is_quorum(&peers(&[2])));
+
+        let match_index = [(1, 7), (2, 4)]
+            .iter()
+            .map(|(peer, index)| (Peer::from(*peer), *index))
+            .collect();
+        assert_eq!(membership.quorum_index(&match_index), 7);
+
+        // Odd numbers of voters can't tie
+        membership.voters.insert(Peer::from(3));
+        assert!(!membership.is_quorum(&peers(&[1])));
+    }
+
+    #[test]
+    fn tie_breakers_come_and_go_through_joint_configurations() {
+        // Without a tie-breaker, single-server changes go straight through
+        let membership = Membership::new(peers(&[1, 2, 3]));
+        let next = membership.next(peers(&[1, 2, 3, 4]), HashSet::new(), true, None);
+        assert_eq!(next, Membership::new(peers(&[1, 2, 3, 4])));
+
+        // Removing 3 with 1 as tie-breaker: 1 alone must not be a quorum until
+        // the old voters have agreed, or 2 and 3 could elect another leader
+        let next = membership.next(peers(&[1, 2]), HashSet::new(), true, tie_breaker(1));
+        assert!(next.is_joint());
+        assert!(!next.is_quorum(&peers(&[1])));
+        assert!(!next.is_quorum(&peers(&[2, 3])));
+        assert!(next.is_quorum(&peers(&[1, 2])));
+
+        let next = next.leave_joint();
+        assert_eq!(next.voters, peers(&[1, 2]));
+        assert_eq!(next.tie_breaker, tie_breaker(1));
+        assert!(next.is_quorum(&peers(&[1])));
+
+        // Handing the tie-breaker over to 2 needs both of them
+        let handover = next.next(peers(&[1, 2]), HashSet::new(), true, tie_breaker(2));
+        assert!(handover.is_joint());
+        assert!(!handover.is_quorum(&peers(&[1])));
+        assert!(!handover.is_quorum(&peers(&[2])));
+        assert_eq!(handover.leave_joint().tie_breaker, tie_breaker(2));
+
+        // Naming the same tie-breaker again is no change
+        assert_eq!(
+            next.next(peers(&[1, 2]), HashSet::new(), true, tie_breaker(1)),
+            next
+        );
+
+        // Adding a third voter drops the tie-breaker, through a joint
+        // configuration too
+        let next = next.next(peers(&[1, 2, 3]), HashSet::new(), true, tie_breaker(1));
+        assert!(next.is_joint());
+        assert!(!next.is_quorum(&peers(&[1])));
+        assert!(next.is_quorum(&peers(&[1, 3])));
+        assert_eq!(next.leave_joint().tie_breaker, None);
+    }
+
+    #[test]
+    fn removed_peers_are_kept_out_until_added_back() {
+        let membership = Membership::new(peers(&[1, 2, 3]));
+        let next = membership
+            .next(peers(&[1, 2]), HashSet::new(), false, None)
+            .removing(peers(&[3]));
+        assert_eq!(next.removed, peers(&[3]));
+
+        // Still kept out once the change completes, and across later ones
+        let next = next.leave_joint();
+        assert_eq!(next.removed, peers(&[3]));
+        let next = next
+            .next(peers(&[1, 2]), peers(&[4]), false, None)
+            .removing(HashSet::new());
+        assert_eq!(next.removed, peers(&[3]));
+
+        let next = next
+            .next(peers(&[1, 2, 3]), peers(&[4]), false, None)
+            .removing(HashSet::new());
+        assert!(next.removed.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+
+/// How the leader is sending entries to a follower
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// The follower's log position isn't known (just elected, or after a
+    /// rejection), so send one message at a time until one is accepted
+    #[default]
+    Probe,
+    /// The follower is keeping up, so send entries as soon as they're
+    /// appended, without waiting for earlier messages to be acknowledged
+    Replicate,
+    /// The follower was sent a snapshot, so only send heartbeats until it's
+    /// acknowledged (or a heartbeat is rejected, showing it never arrived)
+    Snapshot,
+}
+
+/// (Leader) Replication progress of a single follower
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    pub(crate) mode: Mode,
+    /// Last index of each unacknowledged message, oldest first
+    inflight: VecDeque<u32>,
+    /// (Probe) Whether we're waiting on a response before probing again
+    paused: bool,
+}
+
+impl Progress {
+    /// Whether another message with entries can be sent
+    pub(crate) fn can_send(&self, max_inflight: u32) -> bool {
+        match self.mode {
+            Mode::Probe => !self.paused,
+            Mode::Replicate => self.inflight.len() < max_inflight as usize,
+            Mode::Snapshot => false,
+        }
+    }
+
+    /// Record a message (with entries up to `last_index`) being sent
+    pub(crate) fn sent(&mut self, last_index: u32) {
+        match self.mode {
+            Mode::Probe => self.paused = true,
+            Mode::Replicate => self.inflight.push_back(last_index),
+            Mode::Snapshot => {}
+        }
+    }
+
+    /// Record a snapshot being sent, in place of the entries it covers
+    pub(crate) fn sent_snapshot(&mut self) {
+        self.mode = Mode::Snapshot;
+        self.inflight.clear();
+    }
+
+    /// The follower matched up to `match_index`, so everything sent up to
+    /// there has arrived, and it can be sent to freely
+    pub(crate) fn acked(&mut self, match_index: u32) {
+        while self
+            .inflight
+            .front()
+            .is_some_and(|last| *last <= match_index)
+        {
+            self.inflight.pop_front();
+        }
+        self.mode = Mode::Replicate;
+        self.paused = false;
+    }
+
+    /// The follower's log didn't match, so anything in flight will be
+    /// rejected too. Probe until we find where it does match.
+    pub(crate) fn rejected(&mut self) {
+        self.mode = Mode::Probe;
+        self.inflight.clear();
+        self.paused = false;
+    }
+
+    /// A heartbeat interval has passed, so probe again in case the last
+    /// probe (or its response) was lost
+    pub(crate) fn tick(&mut self) {
+        self.paused = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_until_accepted_then_pipelines() {
+        let mut progress = Progress::default();
+        assert!(progress.can_send(2));
+        progress.sent(5);
+        assert!(!progress.can_send(2));
+        progress.tick();
+        assert!(progress.can_send(2));
+
+        progress.acked(3);
+        assert_eq!(progress.mode, Mode::Replicate);
+        progress.sent(6);
+        progress.sent(8);
+        assert!(!progress.can_send(2));
+        progress.acked(6);
+        assert!(progress.can_send(2));
+
+        progress.rejected();
+        assert_eq!(progress.mode, Mode::Probe);
+        assert!(progress.can_send(2));
+    }
+
+    #[test]
+    fn snapshots_pause_until_answered() {
+        let mut progress = Progress::default();
+        progress.sent_snapshot();
+        assert!(!progress.can_send(2));
+        progress.tick();
+        assert!(!progress.can_send(2));
+
+        // A rejected heartbeat means the snapshot was lost
+        progress.rejected();
+        assert!(progress.can_send(2));
+
+        progress.sent_snapshot();
+        progress.acked(10);
+        assert_eq!(progress.mode, Mode::Replicate);
+        assert!(progress.can_send(2));
+    }
+}
@@ -0,0 +1,457 @@
+use futures_channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    log::EntryPayload,
+    raft::{Peer, Role},
+    rpc::{Message, Recipient},
+    Node, NodeState,
+};
+
+/// Where a proposal was applied, and what the state machine returned (if
+/// one is attached)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proposed {
+    pub index: u32,
+    pub term: u32,
+    pub response: Option<serde_json::Value>,
+}
+
+/// Why a proposal wasn't applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposeError {
+    /// The node was stopped before the proposal was applied
+    Stopped,
+    /// The leader that appended the proposal stepped down before it was
+    /// committed. It may still be committed by the next leader.
+    LeadershipLost,
+    /// A new leader replaced the entry with one of its own
+    Overwritten,
+    /// A snapshot covering the entry was installed before this node could
+    /// check it was committed, and the proposal isn't among the session's
+    /// results
+    Compacted,
+    /// This node's session expired before the proposal was applied, so it was
+    /// dropped. Later proposals use a new session.
+    SessionExpired,
+}
+
+impl std::fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProposeError::Stopped => write!(f, "node stopped"),
+            ProposeError::LeadershipLost => write!(f, "leadership lost before commit"),
+            ProposeError::Overwritten => write!(f, "entry overwritten by a new leader"),
+            ProposeError::Compacted => write!(f, "entry compacted before commit was known"),
+            ProposeError::SessionExpired => write!(f, "session expired"),
+        }
+    }
+}
+
+impl std::error::Error for ProposeError {}
+
+pub(crate) type ProposeDone = oneshot::Sender<Result<Proposed, ProposeError>>;
+
+/// A proposal waiting to be applied
+pub(crate) struct PendingProposal<T> {
+    payload: T,
+    /// The leader it was last forwarded to, if any
+    sent_to: Option<Peer>,
+    /// The index and term a leader appended it at, if any
+    accepted: Option<(u32, u32)>,
+    /// How many times it was handed to a leader (forwarded, or appended by
+    /// us). Once that's more than one, another copy may still be committed,
+    /// so a lost entry is retried rather than failed.
+    copies: u32,
+    pub(crate) done: ProposeDone,
+}
+
+impl<T> Node<T>
+where
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
+{
+    /// Give the payload the next sequence number in our session, and append it
+    /// if we're the leader, otherwise forward it to the leader (or hold on to
+    /// it until there is one)
+    pub(crate) fn start_proposal(
+        self: &Arc<Self>,
+        state: &mut NodeState<T>,
+        payload: T,
+        done: ProposeDone,
+    ) {
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.proposals.insert(
+            seq,
+            PendingProposal {
+                payload,
+                sent_to: None,
+                accepted: None,
+                copies: 0,
+                done,
+            },
+        );
+
+        let appended = self.append_proposals(state);
+        if appended > 0 {
+            self.replicate_batched(state, appended);
+        } else {
+            self.forward_proposals(state);
+        }
+    }
+
+    fn append_proposal(
+        &self,
+        state: &mut NodeState<T>,
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+        payload: T,
+    ) -> (u32, u32) {
+        let index = state.append_to_log(EntryPayload::Session {
+            session,
+            seq,
+            settled_below,
+            payload,
+        });
+        (index, state.term)
+    }
+
+    /// (Leader) Append every proposal of ours that no leader has accepted.
+    /// Returns how many there were, so the caller can replicate them.
+    pub(crate) fn append_proposals(&self, state: &mut NodeState<T>) -> u32 {
+        if state.role != Role::Leader || state.transfer_target.is_some() {
+            return 0;
+        }
+
+        let session = state.session;
+        let settled_below = state.settled_below();
+        let waiting = state
+            .proposals
+            .iter()
+            .filter(|(_, proposal)| proposal.accepted.is_none())
+            .map(|(seq, proposal)| (*seq, proposal.payload.clone()))
+            .collect::<Vec<_>>();
+        for (seq, payload) in waiting.iter().cloned() {
+            let accepted = self.append_proposal(state, session, seq, settled_below, payload);
+            if let Some(proposal) = state.proposals.get_mut(&seq) {
+                proposal.accepted = Some(accepted);
+                proposal.copies += 1;
+            }
+        }
+        waiting.len() as u32
+    }
+
+    /// (Follower) Send proposals that no leader has accepted to the current
+    /// leader, unless they've already been sent to it
+    pub(crate) fn forward_proposals(&self, state: &mut NodeState<T>) {
+        let leader = match state.leader {
+            Some(leader) if state.role != Role::Leader && !self.is(&leader) => leader,
+            _ => return,
+        };
+
+        let session = state.session;
+        let settled_below = state.settled_below();
+        for (seq, payload) in state.forward_proposals_to(leader) {
+            self.send(
+                Message::Propose {
+                    session,
+                    seq,
+                    settled_below,
+                    payload,
+                },
+                Recipient::Peer(leader),
+            );
+        }
+    }
+
+    /// Settle proposals whose entries were lost (see
+    /// [`NodeState::settle_proposals`]), and propose again any that need
+    /// retrying. A leader's retries go out with the next heartbeat.
+    pub(crate) fn settle_proposals(&self, state: &mut NodeState<T>) {
+        if state.settle_proposals() && self.append_proposals(state) == 0 {
+            self.forward_proposals(state);
+        }
+    }
+
+    /// (Leader) Append a proposal forwarded by another node, and tell it where
+    pub(crate) fn receive_proposal(
+        self: Arc<Self>,
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+        payload: T,
+        from: Peer,
+    ) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        let response = if state.role == Role::Leader && state.transfer_target.is_none() {
+            let (index, term) =
+                self.append_proposal(&mut state, session, seq, settled_below, payload);
+            self.replicate_batched(&mut state, 1);
+            Message::ProposeResponse {
+                session,
+                seq,
+                accepted: true,
+                index,
+                term,
+            }
+        } else {
+            Message::ProposeResponse {
+                session,
+                seq,
+                accepted: false,
+                index: 0,
+                term: 0,
+            }
+        };
+
+        self.send(response, Recipient::Peer(from));
+    }
+
+    /// The leader accepted a forwarded proposal (or wasn't the leader any more,
+    /// in which case it is retried)
+    pub(crate) fn receive_proposal_response(
+        &self,
+        session: u64,
+        seq: u64,
+        accepted: bool,
+        index: u32,
+        term: u32,
+    ) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        if session != state.session {
+            return;
+        }
+
+        state.record_proposal_response(seq, accepted, index, term);
+        self.settle_proposals(&mut state);
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// Resolve our proposal with sequence number `seq`, if it's still waiting
+    pub(crate) fn resolve_proposal(&mut self, seq: u64, result: Result<Proposed, ProposeError>) {
+        if let Some(proposal) = self.proposals.remove(&seq) {
+            let _ = proposal.done.send(result);
+        }
+    }
+
+    /// (Follower) Mark the proposals that no leader has accepted, and that
+    /// haven't already been sent to `leader`, as sent to it. Returns them, to
+    /// be sent.
+    pub(crate) fn forward_proposals_to(&mut self, leader: Peer) -> Vec<(u64, T)> {
+        let mut forwarded = Vec::new();
+        for (seq, proposal) in self.proposals.iter_mut() {
+            if proposal.accepted.is_none() && proposal.sent_to != Some(leader) {
+                proposal.sent_to = Some(leader);
+                proposal.copies += 1;
+                forwarded.push((*seq, proposal.payload.clone()));
+            }
+        }
+        forwarded
+    }
+
+    /// (Follower) The leader appended our proposal `seq` at `index` in
+    /// `term`, or refused it (e.g. while handing leadership over), in which
+    /// case it's sent again to the next leader we hear from
+    pub(crate) fn record_proposal_response(
+        &mut self,
+        seq: u64,
+        accepted: bool,
+        index: u32,
+        term: u32,
+    ) {
+        if let Some(proposal) = self.proposals.get_mut(&seq) {
+            if accepted {
+                proposal.accepted = Some((index, term));
+            } else {
+                proposal.sent_to = None;
+            }
+        }
+    }
+
+    /// Every proposal of ours below this sequence number has been resolved
+    pub(crate) fn settled_below(&self) -> u64 {
+        self.proposals
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.next_seq)
+    }
+
+    /// (Leader) Fail our own entries that haven't been committed, when
+    /// stepping down
+    pub(crate) fn fail_uncommitted_proposals(&mut self) {
+        let (term, commit_index) = (self.term, self.commit_index);
+        let lost = self
+            .proposals
+            .iter()
+            .filter(|(_, proposal)| {
+                matches!(proposal.accepted, Some((index, accepted_term))
+                    if accepted_term == term && index > commit_index)
+            })
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        for seq in lost {
+            self.resolve_proposal(seq, Err(ProposeError::LeadershipLost));
+        }
+    }
+
+    /// Accepted proposals are resolved as their entries are applied. Any
+    /// still waiting once their index has been applied lost their place in
+    /// the log to another entry (or it was compacted before we could tell),
+    /// unless the session's results show they were applied elsewhere.
+    /// Proposals that another leader may still hold a copy of are retried
+    /// instead of failed. Returns whether there are any to retry.
+    pub(crate) fn settle_proposals(&mut self) -> bool {
+        let last_applied = self.last_applied;
+        let settled = self
+            .proposals
+            .iter()
+            .filter_map(|(seq, proposal)| match proposal.accepted {
+                Some((index, term)) if index <= last_applied => Some((*seq, index, term)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut retry = false;
+        for (seq, index, term) in settled {
+            let result = match self.sessions.result(self.session, seq) {
+                Some(result) => Ok(result),
+                None if self.proposals[&seq].copies > 1 => {
+                    if let Some(proposal) = self.proposals.get_mut(&seq) {
+                        proposal.accepted = None;
+                        proposal.sent_to = None;
+                    }
+                    retry = true;
+                    continue;
+                }
+                None => match self.log.term_at(index) {
+                    Some(entry_term) if entry_term != term => Err(ProposeError::Overwritten),
+                    _ => Err(ProposeError::Compacted),
+                },
+            };
+            self.resolve_proposal(seq, result);
+        }
+        retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Entry;
+
+    #[test]
+    fn proposals_settle_on_commit() {
+        let mut state = NodeState::<u32>::default();
+        state.append_from_leader(vec![
+            Entry {
+                term: 1,
+                index: 1,
+                payload: EntryPayload::Session {
+                    session: state.session,
+                    seq: 1,
+                    settled_below: 1,
+                    payload: 1,
+                },
+            },
+            Entry {
+                term: 2,
+                index: 2,
+                payload: EntryPayload::Normal(2),
+            },
+        ]);
+
+        let mut results = Vec::new();
+        for (seq, accepted) in [(1, (1, 1)), (2, (2, 1)), (3, (3, 2))].iter() {
+            let (done, result) = oneshot::channel();
+            state.proposals.insert(
+                *seq,
+                PendingProposal {
+                    payload: *seq as u32,
+                    sent_to: None,
+                    accepted: Some(*accepted),
+                    copies: 1,
+                    done,
+                },
+            );
+            results.push(result);
+        }
+
+        // Applying our entry resolves it, and the overwritten one is settled
+        let proposed = Proposed {
+            index: 1,
+            term: 1,
+            response: None,
+        };
+        state.commit_index = 2;
+        state.last_applied = 2;
+        state.record_session_entry(state.session, 1, proposed.clone());
+        assert!(!state.settle_proposals());
+        assert_eq!(results[0].try_recv(), Ok(Some(Ok(proposed))));
+        assert_eq!(
+            results[1].try_recv(),
+            Ok(Some(Err(ProposeError::Overwritten)))
+        );
+        assert_eq!(results[2].try_recv(), Ok(None));
+        assert_eq!(state.proposals.len(), 1);
+    }
+
+    #[test]
+    fn proposals_are_forwarded_once_per_leader_until_accepted() {
+        let mut state = NodeState::<u32>::default();
+        let mut results = Vec::new();
+        for seq in 1..=2 {
+            let (done, result) = oneshot::channel();
+            state.proposals.insert(
+                seq,
+                PendingProposal {
+                    payload: seq as u32,
+                    sent_to: None,
+                    accepted: None,
+                    copies: 0,
+                    done,
+                },
+            );
+            results.push(result);
+        }
+
+        let (leader, next_leader) = (Peer::from(2), Peer::from(3));
+        assert_eq!(state.forward_proposals_to(leader), vec![(1, 1), (2, 2)]);
+        assert_eq!(state.forward_proposals_to(leader), vec![]);
+
+        state.record_proposal_response(1, true, 4, 1);
+        state.record_proposal_response(2, false, 0, 0);
+        assert_eq!(state.proposals[&1].accepted, Some((4, 1)));
+        assert_eq!(state.forward_proposals_to(leader), vec![(2, 2)]);
+        assert_eq!(state.forward_proposals_to(next_leader), vec![(2, 2)]);
+        assert_eq!(state.proposals[&2].copies, 3);
+    }
+
+    #[test]
+    fn lost_proposals_with_other_copies_are_retried() {
+        let mut state = NodeState::<u32>::default();
+        let (done, mut result) = oneshot::channel();
+        state.proposals.insert(
+            1,
+            PendingProposal {
+                payload: 1,
+                sent_to: Some(Peer::from(2)),
+                accepted: Some((1, 1)),
+                copies: 2,
+                done,
+            },
+        );
+
+        state.last_applied = 1;
+        assert!(state.settle_proposals());
+        assert_eq!(result.try_recv(), Ok(None));
+        assert_eq!(state.proposals[&1].accepted, None);
+        assert_eq!(state.settled_below(), 1);
+    }
+}
@@ -1,19 +1,40 @@
 use gloo::timers::callback::Timeout;
+use rand::{rngs::OsRng, Rng};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, sync::Arc};
+use std::{cell::Cell, collections::HashSet, rc::Rc, sync::Arc};
 
 use crate::{
+    log::{Entry, EntryPayload, Snapshot},
+    membership::MembershipError,
+    progress::Mode,
+    propose::Proposed,
     rpc::{Message, Recipient},
     NodeState,
 };
 
 use super::Node;
 
+/// Election timeouts back off to at most 2^this times their drawn value
+const MAX_ELECTION_BACKOFF: u32 = 3;
+
+/// Draw an election timeout (in ms) from `range`
+pub(crate) fn draw_election_timeout((low, high): (u32, u32)) -> u32 {
+    if low < high {
+        OsRng::new()
+            .expect("failed to create RNG")
+            .gen_range(low, high)
+    } else {
+        low
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub enum Role {
     Follower,
     Candidate,
     Leader,
+    /// Receives the log, but never votes or stands for election
+    Learner,
 }
 
 impl std::fmt::Display for Role {
@@ -22,6 +43,7 @@ impl std::fmt::Display for Role {
             Role::Follower => write!(f, "Follower"),
             Role::Candidate => write!(f, "Candidate"),
             Role::Leader => write!(f, "Leader"),
+            Role::Learner => write!(f, "Learner"),
         }
     }
 }
@@ -35,9 +57,15 @@ impl Peer {
     }
 }
 
+impl From<u32> for Peer {
+    fn from(id: u32) -> Self {
+        Peer(id)
+    }
+}
+
 impl<T> Node<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     pub fn peer(&self) -> Peer {
         Peer(self.id)
@@ -47,18 +75,24 @@ where
         peer.id() == self.id
     }
 
-    pub(crate) fn add_peer(&self, peer: Peer) {
+    /// A node joined the channel. The leader adds it to the configuration
+    /// (once any other change has finished).
+    pub(crate) fn add_peer(self: Arc<Self>, peer: Peer, learner: bool) {
         let mut state = self.state.lock().expect("poisoned mutex!");
-        state.peers.insert(peer);
+        state.admit_peer(peer, learner);
 
         if state.role == Role::Leader {
-            self.send(Message::PeerSet(state.peers.clone()), Recipient::Everyone)
+            self.send(Message::PeerSet(state.peers.clone()), Recipient::Everyone);
+            self.reconcile_membership(&mut state);
         }
     }
 
-    pub(crate) fn remove_peer(&self, peer: Peer) {
+    /// A node left the channel. The leader removes it from the configuration
+    /// (once any other change has finished).
+    pub(crate) fn remove_peer(self: Arc<Self>, peer: Peer) {
         let mut state = self.state.lock().expect("poisoned mutex!");
-        state.peers.remove(&peer);
+        state.forget_peer(peer);
+        self.reconcile_membership(&mut state);
     }
 
     /// When the leader sees PeerAdded message, it sends out a PeerSet response
@@ -68,8 +102,23 @@ where
         state.peers = peers;
     }
 
-    pub(crate) fn new_election_task(self: Arc<Self>) -> Timeout {
-        Timeout::new(self.election_timeout_ms, || self.start_election())
+    fn new_election_task(self: Arc<Self>, timeout_ms: u32) -> Timeout {
+        Timeout::new(timeout_ms, || self.start_election())
+    }
+
+    /// The shortest election timeout any node could draw (assuming they're
+    /// configured alike), which timers that must fire before any election
+    /// (like the quorum check) are based on
+    pub(crate) fn min_election_timeout_ms(&self) -> u32 {
+        self.election_timeout_ms_range.0
+    }
+
+    /// (Re)start the election timer, with a timeout drawn afresh from the
+    /// configured range and doubled for each failed election in a row
+    pub(crate) fn reset_election_timer(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let range = state.election_timeout_range(self.election_timeout_ms_range);
+        let timeout_ms = draw_election_timeout(range);
+        state.replace_election_task(Some(self.clone().new_election_task(timeout_ms)));
     }
 
     /// When election times out (the node hasn't heard from it's leader in the
@@ -77,109 +126,320 @@ where
     /// election.
     fn start_election(self: Arc<Self>) {
         let mut state = self.state.lock().expect("poisoned mutex!");
-        match state.peers.len() {
-            // When only node, automatically win the election
-            1 => {
-                drop(state);
-                self.clone().win_election();
-            }
+        let membership = state.membership();
 
-            // When two nodes, we could possibly deadlock. Default to the node with lower id
-            2 => {
-                // This requires waiting until the lower node's election times out
-                let mut iter = state.peers.iter();
-                let lower = std::cmp::min(iter.next().unwrap(), iter.next().unwrap());
-                if self.peer().eq(lower) {
-                    drop(state);
-                    self.clone().win_election();
-                }
+        // Learners and nodes that aren't voters (yet) never stand for election
+        let me = self.peer();
+        if !membership.contains(&me) {
+            self.reset_election_timer(&mut state);
+            if let Some(reminder) = state.rejoin_message(me) {
+                self.send(reminder, Recipient::Everyone);
             }
+            return;
+        }
 
-            // With at least 3 nodes, elect normally
-            _ => {
-                let candidate = self.peer();
-                state.role = Role::Candidate;
-                state.term += 1;
-                state.votes.insert(self.peer());
-                state.voted_for = Some(candidate);
-                state.election_task = Some(self.clone().new_election_task());
+        state.count_failed_election();
 
-                self.call_on_role_change(Role::Candidate);
+        // Forget the old leader, since we haven't heard from it
+        state.leader = None;
+        if self.pre_vote {
+            self.start_pre_vote(&mut state);
+        } else {
+            self.become_candidate(&mut state);
+        }
+    }
 
-                self.send(
-                    Message::VoteRequest {
-                        term: state.term,
-                        candidate,
-                    },
-                    Recipient::Everyone,
-                );
-            }
+    /// Ask whether the other nodes would vote for us, without disrupting the
+    /// cluster by incrementing our term. The real election only starts once a
+    /// majority agree.
+    fn start_pre_vote(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let candidate = self.peer();
+        state.pre_votes.clear();
+        state.pre_votes.insert(candidate);
+        self.reset_election_timer(state);
+
+        // A lone voter (or a tie-breaker with half the votes) needs no one else
+        if state.membership().is_quorum(&state.pre_votes) {
+            self.become_candidate(state);
+            return;
+        }
+
+        self.send(
+            Message::PreVoteRequest {
+                term: state.term + 1,
+                candidate,
+                last_log_index: state.log.last_index(),
+                last_log_term: state.log.last_term(),
+            },
+            Recipient::Everyone,
+        );
+    }
+
+    /// Increment our term, vote for ourselves and ask everyone else to
+    fn become_candidate(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let candidate = self.peer();
+        self.set_role(state, Role::Candidate);
+        state.term += 1;
+        state.pre_votes.clear();
+        state.votes.clear();
+        state.votes.insert(candidate);
+        state.voted_for = Some(candidate);
+        state.save_hard_state();
+        self.reset_election_timer(state);
+
+        let request = Message::VoteRequest {
+            term: state.term,
+            candidate,
+            last_log_index: state.log.last_index(),
+            last_log_term: state.log.last_term(),
+        };
+        self.send_persisted(state, request, Recipient::Everyone);
+
+        if state.membership().is_quorum(&state.votes) {
+            self.win_election(state);
+        }
+    }
+
+    /// Another node wants to know if we'd vote for it in `term`
+    pub(crate) fn receive_pre_vote_request(
+        &self,
+        term: u32,
+        candidate: Peer,
+        last_log_index: u32,
+        last_log_term: u32,
+    ) {
+        let state = self.state.lock().expect("poisoned mutex!");
+        if state.grants_pre_vote(term, last_log_index, last_log_term) {
+            self.send(
+                Message::PreVoteResponse {
+                    term,
+                    candidate,
+                    follower: self.peer(),
+                },
+                Recipient::Peer(candidate),
+            );
+        }
+    }
+
+    /// Receive a pre-vote from the given follower, and start a real election
+    /// once a majority have agreed
+    pub(crate) fn receive_pre_vote(self: Arc<Self>, term: u32, follower: Peer) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        if state.count_pre_vote(term, follower) {
+            self.become_candidate(&mut state);
         }
     }
 
     /// Receive a vote from the given follower
     pub(crate) fn receive_vote(self: Arc<Self>, term: u32, follower: Peer) {
         let mut state = self.state.lock().expect("poisoned mutex!");
-        if state.role != Role::Candidate {
+        // A vote from a later term means an election we're not part of
+        if self.observe_term(&mut state, term) {
             return;
         }
-
-        match term.cmp(&state.term) {
-            // Got vote for a future term // TODO?
-            std::cmp::Ordering::Greater => {}
-            // Got vote for the current term: continue
-            std::cmp::Ordering::Equal => {}
-            // Got vote for previous term. Ignore
-            std::cmp::Ordering::Less => return,
+        if state.role != Role::Candidate || term < state.term {
+            return;
         }
 
         state.votes.insert(follower);
 
-        if state.votes.len() > (state.peers.len() / 2) {
-            drop(state);
-            self.win_election()
+        if state.membership().is_quorum(&state.votes) {
+            self.win_election(&mut state);
         }
     }
 
     /// Win the current election and start sending out heartbeats
-    fn win_election(self: Arc<Self>) {
-        {
-            let mut state = self.state.lock().expect("poisoned mutex!");
-            // if state.role != Role::Candidate {
-            //     return;
-            // }
+    fn win_election(self: &Arc<Self>, state: &mut NodeState<T>) {
+        state.leader = Some(self.peer());
+        state.failed_elections = 0;
+        state.votes.clear();
+        state.election_task = None;
+
+        // Start by assuming every peer's log matches ours, and back up from
+        // there on rejection
+        let next_index = state.log.last_index() + 1;
+        state.next_index = state
+            .membership()
+            .peers()
+            .into_iter()
+            .map(|peer| (peer, next_index))
+            .collect();
+        state.match_index.clear();
+        state.progress.clear();
+
+        // Commit a blank entry, so entries from previous terms are committed
+        state.append_to_log(EntryPayload::Blank);
+        self.append_proposals(state);
+
+        state.recent_peers.clear();
+        state.replace_quorum_task(Some(self.clone().new_quorum_task()));
+
+        self.set_role(state, Role::Leader);
+        self.replicate(state);
+        state.replace_heartbeat_task(Some(self.clone().new_heartbeat_task()));
+    }
+
+    pub(crate) fn new_quorum_task(self: Arc<Self>) -> Timeout {
+        Timeout::new(self.min_election_timeout_ms(), || self.check_quorum())
+    }
+
+    /// (Leader) Once per election timeout, make sure a majority of peers have
+    /// responded since the last check. If they haven't, this node may have
+    /// been cut off from the rest of the cluster (which could have elected a
+    /// new leader), so step down.
+    fn check_quorum(self: Arc<Self>) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        if state.role != Role::Leader {
+            return;
+        }
+
+        if state.heard_from_quorum(self.peer()) {
+            self.detect_failures(&mut state);
+            state.replace_quorum_task(Some(self.clone().new_quorum_task()));
+        } else {
+            self.become_follower(&mut state);
+        }
+    }
+
+    /// Step down from leader (or candidate), and wait to hear from a new leader
+    pub(crate) fn become_follower(self: &Arc<Self>, state: &mut NodeState<T>) {
+        state.leader = None;
+        state.votes.clear();
+        state.transfer_target = None;
+        state.fail_reads();
+        state.fail_uncommitted_proposals();
+        self.drop_lease(state);
+        state.fail_membership_change(MembershipError::LeadershipLost);
+        state.last_seen.clear();
+        state.suspects.clear();
+        state.replace_heartbeat_task(None);
+        state.replace_quorum_task(None);
+        state.replace_transfer_task(None);
+        state.replace_batch_task(None);
+        state.batched = 0;
+        self.reset_election_timer(state);
+
+        self.set_role(state, Role::Follower);
+    }
+
+    /// Adopt `term` if it's newer than ours: forget our vote, and stop leading
+    /// (or campaigning). Every message carrying a term goes through this.
+    /// Returns whether the term changed.
+    pub(crate) fn observe_term(self: &Arc<Self>, state: &mut NodeState<T>, term: u32) -> bool {
+        if !state.adopt_term(term) {
+            return false;
+        }
+
+        if state.role == Role::Leader || state.role == Role::Candidate {
+            self.become_follower(state);
+        }
+        true
+    }
+
+    /// Change role, notifying `on_role_change` only if it actually changed
+    pub(crate) fn set_role(&self, state: &mut NodeState<T>, role: Role) {
+        if state.role != role {
+            state.role = role;
+            self.call_on_role_change(role);
+        }
+    }
+
+    /// (Leader) Hand leadership over to `to` (or the most up-to-date peer).
+    /// New proposals are dropped until the transfer completes, or is aborted
+    /// after an election timeout.
+    pub(crate) fn start_transfer(self: &Arc<Self>, state: &mut NodeState<T>, to: Option<Peer>) {
+        if state.role != Role::Leader || state.transfer_target.is_some() {
+            return;
+        }
+
+        let target = match state.transfer_target_for(self.peer(), to) {
+            Some(target) => target,
+            None => return,
+        };
+
+        state.transfer_target = Some(target);
+        self.drop_lease(state);
+        let node = self.clone();
+        state.replace_transfer_task(Some(Timeout::new(
+            self.min_election_timeout_ms(),
+            move || node.abort_transfer(),
+        )));
+
+        // Bring the target up to date first, if it isn't already
+        if !self.try_timeout_now(state, target) {
+            self.send_append_entries(state, target);
+        }
+    }
+
+    /// (Leader) Tell the transfer target to start an election, if its log has
+    /// caught up with ours. Returns whether it was sent.
+    fn try_timeout_now(&self, state: &NodeState<T>, target: Peer) -> bool {
+        if !state.has_caught_up(target) {
+            return false;
+        }
+
+        self.send(
+            Message::TimeoutNow { term: state.term },
+            Recipient::Peer(target),
+        );
+        true
+    }
 
-            state.role = Role::Leader;
-            state.voted_for = None;
-            state.votes.clear();
-            state.election_task = None;
+    /// (Leader) The transfer took too long, so start accepting proposals again
+    fn abort_transfer(self: Arc<Self>) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        state.transfer_target = None;
+        state.transfer_task = None;
 
-            self.call_on_role_change(Role::Leader);
+        // Proposals held during the transfer can go in now
+        if state.role == Role::Leader && self.append_proposals(&mut state) > 0 {
+            self.replicate(&mut state);
+        }
+    }
+
+    /// The leader is handing leadership to us, so start an election right
+    /// away. Pre-vote is skipped, since the leader has already agreed.
+    pub(crate) fn receive_timeout_now(self: Arc<Self>, term: u32, leader: Peer) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        self.observe_term(&mut state, term);
+        if state.accepts_timeout_now(term, leader) {
+            self.become_candidate(&mut state);
         }
-        self.send_heartbeat();
     }
 
-    pub(crate) fn receive_vote_request(self: Arc<Self>, term: u32, candidate: Peer) {
+    /// Vote for `candidate` if we haven't voted for anyone else this term, and
+    /// its log has everything ours does (so a stale node can't be elected and
+    /// overwrite committed entries)
+    pub(crate) fn receive_vote_request(
+        self: Arc<Self>,
+        term: u32,
+        candidate: Peer,
+        last_log_index: u32,
+        last_log_term: u32,
+    ) {
         let mut state = self.state.lock().expect("poisoned mutex!");
 
         if self.peer() == candidate {
             return;
         }
 
-        // Update term
-        match term.cmp(&state.term) {
-            std::cmp::Ordering::Less => return,
-            std::cmp::Ordering::Equal => (),
-            std::cmp::Ordering::Greater => {
-                state.term = term;
-                state.voted_for = None;
-                state.votes.clear();
-            }
+        // Someone else is starting a newer term (e.g. after we handed
+        // leadership over), so stop leading
+        self.observe_term(&mut state, term);
+        if term < state.term {
+            return;
         }
 
-        if state.role == Role::Follower && state.voted_for.is_none() {
+        // Candidates (and leaders) have already voted for themselves this term
+        let grant = state.role == Role::Follower
+            && (state.voted_for.is_none() || state.voted_for == Some(candidate))
+            && state.log.is_up_to_date(last_log_index, last_log_term);
+        if grant {
+            // The vote must be durable before it's sent
             state.voted_for = Some(candidate);
-            self.send(
+            state.save_hard_state();
+            self.send_persisted(
+                &mut state,
                 Message::VoteResponse {
                     term,
                     candidate,
@@ -187,8 +447,8 @@ where
                 },
                 Recipient::Peer(candidate),
             );
+            self.reset_election_timer(&mut state);
         }
-        state.replace_election_task(Some(self.clone().new_election_task()));
     }
 
     pub(crate) fn new_heartbeat_task(self: Arc<Self>) -> Timeout {
@@ -197,51 +457,642 @@ where
 
     fn send_heartbeat(self: Arc<Self>) {
         let mut state = self.state.lock().expect("poisoned mutex!");
-        self.send(Message::Heartbeat { term: state.term }, Recipient::Everyone);
+        for progress in state.progress.values_mut() {
+            progress.tick();
+        }
+        // Entries held back for a batch wait for it, like any others
+        self.send_round(&mut state);
         state.replace_heartbeat_task(Some(self.clone().new_heartbeat_task()));
     }
 
-    pub(crate) fn receive_hearbeat(self: Arc<Self>, term: u32, _leader: &Peer) {
+    /// Send each peer the entries it is missing, including any held back for
+    /// a batch (or an empty heartbeat, if it is up to date)
+    pub(crate) fn replicate(self: &Arc<Self>, state: &mut NodeState<T>) {
+        // Anything held back for a batch goes out now
+        state.batched = 0;
+        state.replace_batch_task(None);
+        self.send_round(state);
+    }
+
+    /// Send each peer the entries it is missing, apart from those held back
+    /// for a batch
+    fn send_round(self: &Arc<Self>, state: &mut NodeState<T>) {
+        self.flush_own_entries(state);
+        state.heartbeat_round += 1;
+        self.record_round_sent(state);
+
+        for peer in state.replication_targets() {
+            if !self.is(&peer) {
+                self.send_append_entries(state, peer);
+            }
+        }
+
+        // A lone leader can commit without hearing from anyone
+        self.advance_commit_index(state);
+    }
+
+    /// (Leader) Flush our log to storage, and only count it towards a
+    /// majority once it's durable, just like a follower's
+    fn flush_own_entries(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let (term, last_index) = (state.term, state.log.last_index());
+        if state.match_index.get(&self.peer()).copied().unwrap_or(0) >= last_index {
+            return;
+        }
+
+        // Storage that writes synchronously calls back before `flush` returns,
+        // while we still hold the lock, so that's handled here instead
+        let returned = Rc::new(Cell::new(false));
+        let durable = Rc::new(Cell::new(false));
+        let node = self.clone();
+        let (flush_returned, flush_durable) = (returned.clone(), durable.clone());
+        state.storage.flush(Box::new(move || {
+            if flush_returned.get() {
+                node.own_entries_durable(term, last_index);
+            } else {
+                flush_durable.set(true);
+            }
+        }));
+        returned.set(true);
+
+        if durable.get() {
+            self.match_own_entries(state, term, last_index);
+        }
+    }
+
+    /// (Leader) Our log up to `index` was flushed
+    fn own_entries_durable(self: Arc<Self>, term: u32, index: u32) {
         let mut state = self.state.lock().expect("poisoned mutex!");
+        if self.match_own_entries(&mut state, term, index) {
+            self.advance_commit_index(&mut state);
+        }
+    }
 
+    /// (Leader) Record our own log as matching up to `index`, if we're still
+    /// leading the term it was flushed in. Returns whether it moved forward.
+    fn match_own_entries(&self, state: &mut NodeState<T>, term: u32, index: u32) -> bool {
+        if state.role != Role::Leader || state.term != term {
+            return false;
+        }
+
+        let matched = state.match_index.entry(self.peer()).or_insert(0);
+        if index <= *matched {
+            return false;
+        }
+        *matched = index;
+        true
+    }
+
+    /// (Leader) Replicate `appended` new entries, or hold them back so they go
+    /// out (and commit) together with any that follow within the batching
+    /// window. The batch is sent once it's `max_batch_entries` long, or
+    /// `batch_delay_ms` after its first entry, whichever is sooner.
+    pub(crate) fn replicate_batched(self: &Arc<Self>, state: &mut NodeState<T>, appended: u32) {
+        if self.batch_delay_ms == 0 || state.add_to_batch(appended, self.max_batch_entries) {
+            self.replicate(state);
+            return;
+        }
+
+        if state.batch_task.is_none() {
+            let node = self.clone();
+            state.replace_batch_task(Some(Timeout::new(self.batch_delay_ms, move || {
+                node.flush_batch()
+            })));
+        }
+    }
+
+    /// (Leader) The batching window closed
+    fn flush_batch(self: Arc<Self>) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        state.batch_task = None;
+        if state.role == Role::Leader && state.batched > 0 {
+            self.replicate(&mut state);
+        }
+    }
+
+    /// Send `peer` the entries from its `next_index` onward, in messages of
+    /// at most `max_append_entries`, for as long as its progress allows (or
+    /// the latest snapshot, if some of those entries have been compacted).
+    /// Sends an empty heartbeat if nothing else could be sent.
+    fn send_append_entries(&self, state: &mut NodeState<T>, peer: Peer) {
+        let last_index = state.sendable_index();
+        let mut next_index = state
+            .next_index
+            .get(&peer)
+            .copied()
+            .unwrap_or(last_index + 1);
+        let mut progress = state.progress.remove(&peer).unwrap_or_default();
+
+        // Once sent, the snapshot stands in for the entries it covers, and
+        // only heartbeats follow until it's acknowledged
+        if let Some(ref snapshot) = state.snapshot {
+            if next_index <= state.log.compacted_index()
+                && progress.can_send(self.max_inflight_appends)
+            {
+                progress.sent_snapshot();
+                self.send(
+                    Message::InstallSnapshot {
+                        term: state.term,
+                        snapshot: Box::new(snapshot.clone()),
+                    },
+                    Recipient::Peer(peer),
+                );
+                next_index = snapshot.index + 1;
+            }
+        }
+
+        let mut sent = false;
+        while next_index <= last_index && progress.can_send(self.max_inflight_appends) {
+            let entries = state
+                .log
+                .entries_between(next_index, self.max_append_entries);
+            let prev_log_index = next_index - 1;
+            let sent_to = prev_log_index + entries.len() as u32;
+            self.send_entries(state, peer, prev_log_index, entries);
+            progress.sent(sent_to);
+            sent = true;
+
+            // Only move ahead of what the follower has acknowledged once it's
+            // keeping up
+            if progress.mode != Mode::Replicate {
+                break;
+            }
+            next_index = sent_to + 1;
+        }
+        if !sent {
+            self.send_entries(state, peer, next_index - 1, Vec::new());
+        }
+
+        state.next_index.insert(peer, next_index);
+        state.progress.insert(peer, progress);
+    }
+
+    fn send_entries(
+        &self,
+        state: &NodeState<T>,
+        peer: Peer,
+        prev_log_index: u32,
+        entries: Vec<Entry<T>>,
+    ) {
+        self.send(
+            Message::AppendEntries {
+                term: state.term,
+                prev_log_index,
+                prev_log_term: state.log.term_at(prev_log_index).unwrap_or(0),
+                entries,
+                leader_commit: state.commit_index,
+                round: state.heartbeat_round,
+            },
+            Recipient::Peer(peer),
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn receive_append_entries(
+        self: Arc<Self>,
+        term: u32,
+        leader: Peer,
+        prev_log_index: u32,
+        prev_log_term: u32,
+        entries: Vec<Entry<T>>,
+        leader_commit: u32,
+        round: u64,
+    ) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        self.observe_term(&mut state, term);
+
+        // Reject entries from a stale leader
+        if term < state.term {
+            self.send(
+                Message::AppendEntriesResponse {
+                    term: state.term,
+                    success: false,
+                    match_index: 0,
+                    conflict_index: 0,
+                    conflict_term: None,
+                    round,
+                },
+                Recipient::Peer(leader),
+            );
+            return;
+        }
+
+        if !self.follow_leader(&mut state, term, leader) {
+            return;
+        }
+
+        // Consistency check: only append if our log matches the leader's up to
+        // `prev_log_index`, otherwise tell the leader where to back up to
+        let success = state.log.matches(prev_log_index, prev_log_term);
+        let mut match_index = 0;
+        let (mut conflict_index, mut conflict_term) = (0, None);
+        if success {
+            match_index = prev_log_index + entries.len() as u32;
+            state.append_from_leader(entries);
+            self.update_learner_role(&mut state);
+
+            let commit_index = leader_commit.min(match_index);
+            if commit_index > state.commit_index {
+                state.commit_index = commit_index;
+                self.apply_committed(&mut state);
+            }
+        } else {
+            let (index, term) = state.log.conflict_hint(prev_log_index);
+            conflict_index = index;
+            conflict_term = term;
+        }
+
+        // Only acknowledge entries once they're durable
+        let response = Message::AppendEntriesResponse {
+            term: state.term,
+            success,
+            match_index,
+            conflict_index,
+            conflict_term,
+            round,
+        };
+        self.send_persisted(&mut state, response, Recipient::Peer(leader));
+
+        self.reset_election_timer(&mut state);
+    }
+
+    /// Replace the state machine and log with a snapshot sent by the leader
+    pub(crate) fn receive_install_snapshot(
+        self: Arc<Self>,
+        term: u32,
+        leader: Peer,
+        snapshot: Snapshot,
+    ) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        self.observe_term(&mut state, term);
+
+        let success = term >= state.term;
+        if success && !self.follow_leader(&mut state, term, leader) {
+            return;
+        }
+
+        let match_index = snapshot.index;
+        if success && snapshot.index > state.commit_index {
+            if let Some(ref mut state_machine) = state.state_machine {
+                state_machine.restore(snapshot.data.clone());
+            }
+            state.commit_index = snapshot.index;
+            state.last_applied = snapshot.index;
+            state.install_snapshot(snapshot);
+            self.update_learner_role(&mut state);
+            self.settle_proposals(&mut state);
+        }
+
+        let response = Message::AppendEntriesResponse {
+            term: state.term,
+            success,
+            match_index,
+            conflict_index: 0,
+            conflict_term: None,
+            round: 0,
+        };
+        self.send_persisted(&mut state, response, Recipient::Peer(leader));
+
+        if success {
+            self.reset_election_timer(&mut state);
+        }
+    }
+
+    /// Update this node's role after hearing from the leader of `term` (which
+    /// has already been adopted if it was newer). Returns false if the message
+    /// should be ignored.
+    fn follow_leader(&self, state: &mut NodeState<T>, term: u32, leader: Peer) -> bool {
         match state.role {
-            // Leader's own heartbeat
-            Role::Leader => return,
+            // There's only one leader per term, so this must be our own
+            Role::Leader => return false,
 
-            // Someone else won an election
+            // Someone else won this term's election
             Role::Candidate => {
-                if term >= state.term {
-                    state.role = Role::Follower;
-                    state.voted_for = None;
-                    state.votes.clear();
-                    self.call_on_role_change(Role::Follower);
-                }
+                state.votes.clear();
+                self.set_role(state, Role::Follower);
             }
 
-            // Update term if there's a new term
-            Role::Follower => {
-                if term > state.term {
-                    state.term = term;
-                    state.voted_for = None;
+            Role::Follower | Role::Learner => {}
+        }
+        debug_assert_eq!(term, state.term);
+        state.heard_from_leader(leader);
+        self.forward_proposals(state);
+        true
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn receive_append_entries_response(
+        self: &Arc<Self>,
+        term: u32,
+        follower: Peer,
+        success: bool,
+        match_index: u32,
+        conflict_index: u32,
+        conflict_term: Option<u32>,
+        round: u64,
+    ) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        // A follower that has moved on to a later term means we've been
+        // replaced
+        self.observe_term(&mut state, term);
+        if state.role != Role::Leader || term != state.term {
+            return;
+        }
+
+        // Any response shows the follower can still hear us (and still
+        // recognises us as leader)
+        state.recent_peers.insert(follower);
+        self.mark_seen(&mut state, follower);
+        self.receive_read_ack(&mut state, follower, round);
+
+        let matched = state.match_index.get(&follower).copied().unwrap_or(0);
+        let next_index = state
+            .next_index
+            .get(&follower)
+            .copied()
+            .unwrap_or_else(|| state.log.last_index() + 1);
+
+        if success {
+            state
+                .progress
+                .entry(follower)
+                .or_default()
+                .acked(match_index);
+            if match_index > matched {
+                state.match_index.insert(follower, match_index);
+                self.advance_commit_index(&mut state);
+            }
+            if match_index + 1 > next_index {
+                state.next_index.insert(follower, match_index + 1);
+            }
+            // Keep the pipeline full, if the follower is behind
+            let sendable = state.sendable_index();
+            if state
+                .next_index
+                .get(&follower)
+                .is_some_and(|&next| next <= sendable)
+            {
+                self.send_append_entries(&mut state, follower);
+            }
+            if state.transfer_target == Some(follower) {
+                self.try_timeout_now(&state, follower);
+            }
+            if state.joining.contains(&follower) {
+                self.reconcile_membership(&mut state);
+            }
+        } else {
+            // Back up past the conflict, but never behind what's known to match
+            let retry_index = state
+                .log
+                .next_index_after_conflict(conflict_index, conflict_term)
+                .min(next_index.saturating_sub(1))
+                .max(matched + 1);
+
+            // Stale rejections can't move us backwards, so only resend when the
+            // follower's next index actually changed
+            if retry_index < next_index {
+                state.next_index.insert(follower, retry_index);
+                state.progress.entry(follower).or_default().rejected();
+                self.send_append_entries(&mut state, follower);
+            }
+        }
+    }
+
+    /// (Leader only) Commit the highest entry from the current term that has
+    /// been replicated on a majority of peers
+    fn advance_commit_index(self: &Arc<Self>, state: &mut NodeState<T>) {
+        let commit_index = state.membership().quorum_index(&state.match_index);
+
+        if commit_index > state.commit_index && state.log.term_at(commit_index) == Some(state.term)
+        {
+            state.commit_index = commit_index;
+            self.apply_committed(state);
+            self.advance_membership(state);
+        }
+    }
+
+    /// Schedule newly committed entries to be applied. They're applied from a
+    /// microtask of their own, so that the state machine and `on_received` run
+    /// without the state locked, and are free to call back into the node. (A
+    /// zero timeout would be clamped to a second or more in background tabs.)
+    fn apply_committed(self: &Arc<Self>, state: &mut NodeState<T>) {
+        if state.last_applied < state.commit_index && !state.apply_scheduled {
+            state.apply_scheduled = true;
+            let node = self.clone();
+            wasm_bindgen_futures::spawn_local(async move { node.apply_entries() });
+        }
+    }
+
+    /// Apply committed entries to the state machine and pass them to
+    /// `on_received`, strictly in log order
+    fn apply_entries(self: Arc<Self>) {
+        let mut state = self.state.lock().expect("poisoned mutex!");
+        if !state.apply_scheduled {
+            // Stopped since being scheduled
+            return;
+        }
+        state.apply_scheduled = false;
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let entry = match state.log.get(index) {
+                Some(entry) => entry.clone(),
+                // Not replicated here yet
+                None => break,
+            };
+            state.sessions.expire(index, self.session_expiry);
+
+            let (session, payload) = match entry.payload {
+                EntryPayload::Normal(payload) => (None, payload),
+                EntryPayload::Session {
+                    session,
+                    seq,
+                    settled_below,
+                    payload,
+                } if state.check_session_entry(index, session, seq, settled_below) => {
+                    (Some((session, seq)), payload)
                 }
+                _ => {
+                    state.last_applied = index;
+                    continue;
+                }
+            };
+
+            // The state machine is taken out while it's applied to, so that
+            // the lock can be released
+            let mut state_machine = state.state_machine.take();
+            drop(state);
+            let response = state_machine
+                .as_mut()
+                .map(|state_machine| state_machine.apply(index, payload.clone()));
+            self.call_on_received(payload);
+
+            state = self.state.lock().expect("poisoned mutex!");
+            state.state_machine = state_machine;
+            state.last_applied = index;
+            if let Some((session, seq)) = session {
+                let result = Proposed {
+                    index,
+                    term: entry.term,
+                    response,
+                };
+                state.record_session_entry(session, seq, result);
             }
         }
 
-        state.replace_election_task(Some(self.clone().new_election_task()));
+        self.settle_proposals(&mut state);
+        self.maybe_snapshot(&mut state);
+        self.advance_reads(&mut state);
+    }
+
+    /// Snapshot the state machine and compact the log, once enough entries
+    /// have been applied since the previous snapshot
+    fn maybe_snapshot(&self, state: &mut NodeState<T>) {
+        let snapshot_index = state.snapshot.as_ref().map(|s| s.index).unwrap_or(0);
+        if self.snapshot_threshold == 0
+            || state.last_applied - snapshot_index < self.snapshot_threshold
+        {
+            return;
+        }
+
+        if let Some(ref state_machine) = state.state_machine {
+            let index = state.last_applied;
+            let snapshot = Snapshot {
+                index,
+                term: state.log.term_at(index).expect("applied entry missing"),
+                data: state_machine.snapshot(),
+                membership: state.membership_at(index),
+                sessions: state.sessions.clone(),
+            };
+            state.save_snapshot(snapshot, index.saturating_sub(self.snapshot_retain));
+        }
     }
 }
 
 impl<T> Drop for Node<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     fn drop(&mut self) {
         self.stop();
-        self.send(Message::PeerRemoved, Recipient::Everyone) // TODO: replace peer-removed with a leader heartbeat response count
+        // Leave straight away, rather than waiting to be evicted
+        self.send(Message::PeerRemoved, Recipient::Everyone)
     }
 }
 
-impl NodeState {
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// (Leader) Hold `appended` new entries back for the current batch.
+    /// Returns whether it's now full, and should be sent.
+    pub(crate) fn add_to_batch(&mut self, appended: u32, max_entries: u32) -> bool {
+        self.batched += appended;
+        self.batched >= max_entries
+    }
+
+    /// (Leader) The last entry that can be sent, leaving out any held back
+    /// for the current batch
+    pub(crate) fn sendable_index(&self) -> u32 {
+        self.log.last_index() - self.batched
+    }
+
+    /// Whether we'd agree to a pre-vote for `term`. Only if we haven't heard
+    /// from a leader recently (so a node that was cut off can't unseat a
+    /// healthy leader), and would grant the real vote.
+    pub(crate) fn grants_pre_vote(
+        &self,
+        term: u32,
+        last_log_index: u32,
+        last_log_term: u32,
+    ) -> bool {
+        term > self.term
+            && self.leader.is_none()
+            && self.role != Role::Learner
+            && self.log.is_up_to_date(last_log_index, last_log_term)
+    }
+
+    /// Count `follower`'s pre-vote, if it's for the election we're about to
+    /// start. Returns whether a majority have now agreed.
+    pub(crate) fn count_pre_vote(&mut self, term: u32, follower: Peer) -> bool {
+        if self.role == Role::Leader || self.pre_votes.is_empty() || term != self.term + 1 {
+            return false;
+        }
+
+        self.pre_votes.insert(follower);
+        self.membership().is_quorum(&self.pre_votes)
+    }
+
+    /// (Leader) Whether a majority (counting ourselves, `me`) have responded
+    /// since the last check, which starts the next one
+    pub(crate) fn heard_from_quorum(&mut self, me: Peer) -> bool {
+        self.recent_peers.insert(me);
+        let quorum = self.membership().is_quorum(&self.recent_peers);
+        self.recent_peers.clear();
+        quorum
+    }
+
+    /// (Leader) Who to hand leadership over to: `to`, or else the most
+    /// up-to-date voter we haven't lost touch with. Never ourselves (`me`),
+    /// or a peer that wouldn't be a voter once any change completes.
+    pub(crate) fn transfer_target_for(&self, me: Peer, to: Option<Peer>) -> Option<Peer> {
+        let voters = self.membership().target().clone();
+        let target = to.or_else(|| {
+            voters
+                .iter()
+                .filter(|peer| **peer != me && !self.suspects.contains(peer))
+                .max_by_key(|peer| self.match_index.get(peer).copied().unwrap_or(0))
+                .copied()
+        })?;
+        Some(target).filter(|target| *target != me && voters.contains(target))
+    }
+
+    /// (Leader) Whether `peer` has every entry in our log
+    pub(crate) fn has_caught_up(&self, peer: Peer) -> bool {
+        self.match_index.get(&peer).copied().unwrap_or(0) >= self.log.last_index()
+    }
+
+    /// Whether a `TimeoutNow` is from the leader we're following this `term`
+    pub(crate) fn accepts_timeout_now(&self, term: u32, leader: Peer) -> bool {
+        self.role == Role::Follower && term == self.term && self.leader == Some(leader)
+    }
+
+    /// Move on to `term` if it's newer than ours, with no vote or leader in
+    /// it yet. Returns whether the term changed.
+    pub(crate) fn adopt_term(&mut self, term: u32) -> bool {
+        if term <= self.term {
+            return false;
+        }
+
+        self.term = term;
+        self.voted_for = None;
+        self.leader = None;
+        self.votes.clear();
+        self.pre_votes.clear();
+        self.save_hard_state();
+        true
+    }
+
+    /// Still campaigning from the last timeout means that election failed
+    /// (e.g. the vote was split), so back off
+    pub(crate) fn count_failed_election(&mut self) {
+        if self.role == Role::Candidate || !self.pre_votes.is_empty() {
+            self.failed_elections += 1;
+        }
+    }
+
+    /// `leader` is leading the current term, so elections have succeeded
+    pub(crate) fn heard_from_leader(&mut self, leader: Peer) {
+        self.leader = Some(leader);
+        self.failed_elections = 0;
+        self.pre_votes.clear();
+    }
+
+    /// The range the next election timeout is drawn from: the `configured`
+    /// one, doubled for each failed election in a row (up to a limit)
+    pub(crate) fn election_timeout_range(&self, (low, high): (u32, u32)) -> (u32, u32) {
+        let backoff = 1 << self.failed_elections.min(MAX_ELECTION_BACKOFF);
+        (low.saturating_mul(backoff), high.saturating_mul(backoff))
+    }
+
     pub(crate) fn replace_election_task(&mut self, new_task: Option<Timeout>) {
         if let Some(old_task) = if let Some(new_task) = new_task {
             self.election_task.replace(new_task)
@@ -261,4 +1112,165 @@ impl NodeState {
             old_task.cancel();
         }
     }
+
+    pub(crate) fn replace_quorum_task(&mut self, new_task: Option<Timeout>) {
+        if let Some(old_task) = if let Some(new_task) = new_task {
+            self.quorum_task.replace(new_task)
+        } else {
+            self.quorum_task.take()
+        } {
+            old_task.cancel();
+        }
+    }
+
+    pub(crate) fn replace_transfer_task(&mut self, new_task: Option<Timeout>) {
+        if let Some(old_task) = if let Some(new_task) = new_task {
+            self.transfer_task.replace(new_task)
+        } else {
+            self.transfer_task.take()
+        } {
+            old_task.cancel();
+        }
+    }
+
+    pub(crate) fn replace_batch_task(&mut self, new_task: Option<Timeout>) {
+        if let Some(old_task) = if let Some(new_task) = new_task {
+            self.batch_task.replace(new_task)
+        } else {
+            self.batch_task.take()
+        } {
+            old_task.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Membership;
+
+    /// A node in term 1 of a cluster of `voters`, with just the configuration
+    /// in its log
+    fn cluster(voters: &[u32]) -> NodeState<u32> {
+        let mut state = NodeState {
+            term: 1,
+            ..NodeState::default()
+        };
+        let voters = voters.iter().map(|id| Peer::from(*id)).collect();
+        state.append_to_log(EntryPayload::Membership(Membership::new(voters)));
+        state
+    }
+
+    #[test]
+    fn only_later_terms_are_adopted() {
+        let mut state = cluster(&[1, 2, 3]);
+        state.voted_for = Some(Peer::from(2));
+        state.heard_from_leader(Peer::from(2));
+
+        assert!(!state.adopt_term(0));
+        assert!(!state.adopt_term(1));
+        assert_eq!(state.voted_for, Some(Peer::from(2)));
+        assert_eq!(state.leader, Some(Peer::from(2)));
+
+        assert!(state.adopt_term(3));
+        assert_eq!(state.term, 3);
+        assert_eq!(state.voted_for, None);
+        assert_eq!(state.leader, None);
+        let hard_state = state.storage.load().hard_state;
+        assert_eq!((hard_state.term, hard_state.voted_for), (3, None));
+    }
+
+    #[test]
+    fn pre_votes_are_only_granted_without_a_leader() {
+        let mut state = cluster(&[1, 2, 3]);
+        assert!(state.grants_pre_vote(2, 1, 1));
+        assert!(!state.grants_pre_vote(1, 1, 1));
+        assert!(!state.grants_pre_vote(2, 0, 0));
+
+        state.heard_from_leader(Peer::from(2));
+        assert!(!state.grants_pre_vote(2, 1, 1));
+    }
+
+    #[test]
+    fn pre_votes_need_a_majority_without_changing_the_term() {
+        let mut state = cluster(&[1, 2, 3]);
+        state.pre_votes.insert(Peer::from(1));
+        assert!(!state.count_pre_vote(3, Peer::from(2)));
+        assert!(state.count_pre_vote(2, Peer::from(2)));
+        assert_eq!(state.term, 1);
+    }
+
+    #[test]
+    fn quorum_checks_need_a_majority_each_time() {
+        let mut state = cluster(&[1, 2, 3]);
+        assert!(!state.heard_from_quorum(Peer::from(1)));
+
+        state.recent_peers.insert(Peer::from(3));
+        assert!(state.heard_from_quorum(Peer::from(1)));
+        assert!(!state.heard_from_quorum(Peer::from(1)));
+    }
+
+    #[test]
+    fn leadership_goes_to_the_most_up_to_date_voter() {
+        let mut state = cluster(&[1, 2, 3]);
+        let me = Peer::from(1);
+        state.match_index.insert(Peer::from(2), 1);
+        assert_eq!(state.transfer_target_for(me, None), Some(Peer::from(2)));
+        assert!(state.has_caught_up(Peer::from(2)));
+        assert!(!state.has_caught_up(Peer::from(3)));
+
+        state.suspects.insert(Peer::from(2));
+        assert_eq!(state.transfer_target_for(me, None), Some(Peer::from(3)));
+        assert_eq!(
+            state.transfer_target_for(me, Some(Peer::from(2))),
+            Some(Peer::from(2))
+        );
+        assert_eq!(state.transfer_target_for(me, Some(me)), None);
+        assert_eq!(state.transfer_target_for(me, Some(Peer::from(4))), None);
+    }
+
+    #[test]
+    fn timeout_now_is_only_taken_from_the_current_leader() {
+        let mut state = cluster(&[1, 2, 3]);
+        assert!(!state.accepts_timeout_now(1, Peer::from(1)));
+
+        state.heard_from_leader(Peer::from(1));
+        assert!(state.accepts_timeout_now(1, Peer::from(1)));
+        assert!(!state.accepts_timeout_now(1, Peer::from(3)));
+        assert!(!state.accepts_timeout_now(0, Peer::from(1)));
+    }
+
+    #[test]
+    fn batches_are_held_back_until_full() {
+        let mut state = NodeState::<u32>::default();
+        state.log.append(1, EntryPayload::Blank);
+
+        for payload in 0..2 {
+            state.log.append(1, EntryPayload::Normal(payload));
+            assert!(!state.add_to_batch(1, 3));
+        }
+        assert_eq!(state.sendable_index(), 1);
+
+        state.log.append(1, EntryPayload::Normal(2));
+        assert!(state.add_to_batch(1, 3));
+    }
+
+    #[test]
+    fn election_timeouts_back_off_until_a_leader_is_heard() {
+        let mut state = NodeState::<u32> {
+            role: Role::Candidate,
+            ..NodeState::default()
+        };
+        assert_eq!(state.election_timeout_range((150, 300)), (150, 300));
+
+        state.count_failed_election();
+        assert_eq!(state.election_timeout_range((150, 300)), (300, 600));
+        for _ in 0..5 {
+            state.count_failed_election();
+        }
+        assert_eq!(state.election_timeout_range((150, 300)), (1200, 2400));
+
+        state.heard_from_leader(Peer::from(2));
+        assert_eq!(state.election_timeout_range((150, 300)), (150, 300));
+    }
 }
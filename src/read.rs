@@ -0,0 +1,304 @@
+use futures_channel::oneshot;
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    raft::{Peer, Role},
+    Node, NodeState,
+};
+
+/// Why a read couldn't be served
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadError {
+    /// Only the leader can serve reads
+    NotLeader,
+    /// This node stopped being the leader before the read was confirmed
+    LeadershipLost,
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::NotLeader => write!(f, "not the leader"),
+            ReadError::LeadershipLost => write!(f, "leadership lost during read"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// (Leader only) A read waiting for its leadership check and for the state
+/// machine to catch up
+pub(crate) struct PendingRead {
+    /// The commit index when the read was made
+    index: u32,
+    /// Only acknowledgements of heartbeats from this round onward count
+    round: u64,
+    acks: HashSet<Peer>,
+    confirmed: bool,
+    done: oneshot::Sender<Result<u32, ReadError>>,
+}
+
+impl<T> Node<T>
+where
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
+{
+    /// (Leader) Record a read at the current commit index, and send a round of
+    /// heartbeats to confirm we're still the leader
+    pub(crate) fn start_read(
+        self: &Arc<Self>,
+        state: &mut NodeState<T>,
+        done: oneshot::Sender<Result<u32, ReadError>>,
+    ) {
+        if state.role != Role::Leader {
+            let _ = done.send(Err(ReadError::NotLeader));
+            return;
+        }
+
+        state.add_read(self.peer(), done);
+        self.replicate(state);
+        self.advance_reads(state);
+    }
+
+    /// (Leader) Serve a read straight away if we hold a lease, or fall back to
+    /// a ReadIndex read
+    pub(crate) fn start_lease_read(
+        self: &Arc<Self>,
+        state: &mut NodeState<T>,
+        done: oneshot::Sender<Result<u32, ReadError>>,
+    ) {
+        if !self.has_lease(state) {
+            self.start_read(state, done);
+            return;
+        }
+
+        state.reads.push(PendingRead {
+            index: state.commit_index,
+            round: state.heartbeat_round,
+            acks: HashSet::new(),
+            confirmed: true,
+            done,
+        });
+        self.advance_reads(state);
+    }
+
+    fn has_lease(&self, state: &NodeState<T>) -> bool {
+        self.pre_vote && self.lease_drift_ms.is_some() && state.holds_lease(js_sys::Date::now())
+    }
+
+    /// (Leader) Note when a round of heartbeats is sent, to time the lease
+    /// from
+    pub(crate) fn record_round_sent(&self, state: &mut NodeState<T>) {
+        if self.lease_drift_ms.is_some() {
+            state
+                .round_sent
+                .insert(state.heartbeat_round, js_sys::Date::now());
+        }
+    }
+
+    /// (Leader) Once a majority has acknowledged a round, no other leader can
+    /// be elected until an election timeout after it was sent
+    fn extend_lease(&self, state: &mut NodeState<T>) {
+        let drift_ms = match self.lease_drift_ms {
+            Some(drift_ms) => drift_ms,
+            None => return,
+        };
+        let lease_ms = self.min_election_timeout_ms().saturating_sub(drift_ms);
+        state.extend_lease(self.peer(), lease_ms);
+    }
+
+    /// Drop any lease, e.g. after stepping down or while handing leadership
+    /// over
+    pub(crate) fn drop_lease(&self, state: &mut NodeState<T>) {
+        state.lease_expires = 0.0;
+        state.round_sent.clear();
+        state.acked_round.clear();
+    }
+
+    /// (Leader) Count a follower's acknowledgement of heartbeat `round`
+    /// towards the reads it confirms
+    pub(crate) fn receive_read_ack(&self, state: &mut NodeState<T>, follower: Peer, round: u64) {
+        let acked = state.acked_round.entry(follower).or_insert(0);
+        *acked = (*acked).max(round);
+        self.extend_lease(state);
+
+        state.ack_reads(follower, round);
+        self.advance_reads(state);
+    }
+
+    /// (Leader) Resolve reads once a majority has confirmed our leadership
+    /// and the state machine has applied everything up to their index
+    pub(crate) fn advance_reads(&self, state: &mut NodeState<T>) {
+        for read in state.ready_reads() {
+            let _ = read.done.send(Ok(read.index));
+        }
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// (Leader) Record a read, as leader `me`, to be confirmed by the next
+    /// round of heartbeats. Until an entry from our term commits, entries
+    /// from previous terms may be committed without us knowing, so the read
+    /// waits for our whole log.
+    pub(crate) fn add_read(&mut self, me: Peer, done: oneshot::Sender<Result<u32, ReadError>>) {
+        let index = if self.log.term_at(self.commit_index) == Some(self.term) {
+            self.commit_index
+        } else {
+            self.log.last_index()
+        };
+
+        let mut acks = HashSet::new();
+        acks.insert(me);
+        self.reads.push(PendingRead {
+            index,
+            round: self.heartbeat_round + 1,
+            acks,
+            confirmed: false,
+            done,
+        });
+    }
+
+    /// (Leader) Whether we can still serve reads without confirming our
+    /// leadership, `now`. Not while handing leadership over, or before an
+    /// entry from our term has committed.
+    pub(crate) fn holds_lease(&self, now: f64) -> bool {
+        self.role == Role::Leader
+            && self.transfer_target.is_none()
+            && self.log.term_at(self.commit_index) == Some(self.term)
+            && now < self.lease_expires
+    }
+
+    /// (Leader) Extend the lease to `lease_ms` after the latest round a
+    /// majority (counting ourselves, `me`) has acknowledged
+    fn extend_lease(&mut self, me: Peer, lease_ms: u32) {
+        if self.transfer_target.is_some() {
+            return;
+        }
+
+        let membership = self.membership();
+        let acked_round = &self.acked_round;
+        let confirmed = self.round_sent.keys().rev().copied().find(|round| {
+            let mut acks = acked_round
+                .iter()
+                .filter(|(_, acked)| *acked >= round)
+                .map(|(peer, _)| *peer)
+                .collect::<HashSet<_>>();
+            acks.insert(me);
+            membership.is_quorum(&acks)
+        });
+
+        if let Some(round) = confirmed {
+            let expires = self.round_sent[&round] + f64::from(lease_ms);
+            self.lease_expires = self.lease_expires.max(expires);
+            self.round_sent = self.round_sent.split_off(&(round + 1));
+        }
+    }
+
+    /// (Leader) Count `follower`'s acknowledgement of heartbeat `round`
+    /// towards the reads sent before it
+    fn ack_reads(&mut self, follower: Peer, round: u64) {
+        for read in self.reads.iter_mut() {
+            if read.round <= round {
+                read.acks.insert(follower);
+            }
+        }
+    }
+
+    /// (Leader) Take the reads a majority has confirmed, once the state
+    /// machine has applied everything up to their index
+    fn ready_reads(&mut self) -> Vec<PendingRead> {
+        if self.reads.is_empty() {
+            return Vec::new();
+        }
+
+        let membership = self.membership();
+        let last_applied = self.last_applied;
+        let (ready, waiting) = self.reads.drain(..).partition(|read: &PendingRead| {
+            (read.confirmed || membership.is_quorum(&read.acks)) && read.index <= last_applied
+        });
+        self.reads = waiting;
+        for read in self.reads.iter_mut() {
+            read.confirmed = read.confirmed || membership.is_quorum(&read.acks);
+        }
+        ready
+    }
+
+    /// Fail every outstanding read, after losing leadership
+    pub(crate) fn fail_reads(&mut self) {
+        for read in self.reads.drain(..) {
+            let _ = read.done.send(Err(ReadError::LeadershipLost));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EntryPayload, Membership};
+
+    /// The leader (1) of term 1, in a cluster of 1, 2 and 3
+    fn leader() -> NodeState<u32> {
+        let mut state = NodeState {
+            role: Role::Leader,
+            term: 1,
+            ..NodeState::default()
+        };
+        let voters = [1, 2, 3].iter().map(|id| Peer::from(*id)).collect();
+        state.append_to_log(EntryPayload::Membership(Membership::new(voters)));
+        state
+    }
+
+    #[test]
+    fn reads_wait_for_a_majority_and_the_state_machine() {
+        let mut state = leader();
+        state.commit_index = 1;
+        let (done, mut read) = oneshot::channel();
+        state.add_read(Peer::from(1), done);
+
+        // Acknowledgements of earlier rounds don't confirm the read
+        state.ack_reads(Peer::from(2), 0);
+        assert!(state.ready_reads().is_empty());
+        state.ack_reads(Peer::from(2), 1);
+        assert!(state.ready_reads().is_empty());
+
+        state.last_applied = 1;
+        for read in state.ready_reads() {
+            let _ = read.done.send(Ok(read.index));
+        }
+        assert_eq!(read.try_recv(), Ok(Some(Ok(1))));
+    }
+
+    #[test]
+    fn reads_wait_for_the_whole_log_until_our_term_commits() {
+        let mut state = leader();
+        state.term = 2;
+        state.append_to_log(EntryPayload::Blank);
+        state.commit_index = 1;
+        let (done, _read) = oneshot::channel();
+        state.add_read(Peer::from(1), done);
+        assert_eq!(state.reads[0].index, 2);
+    }
+
+    #[test]
+    fn leases_run_from_the_acknowledged_round_until_they_expire() {
+        let mut state = leader();
+        state.commit_index = 1;
+        state.round_sent.insert(1, 1000.0);
+        state.round_sent.insert(2, 1050.0);
+
+        // Nothing acknowledged yet
+        state.extend_lease(Peer::from(1), 100);
+        assert!(!state.holds_lease(1000.0));
+
+        state.acked_round.insert(Peer::from(3), 1);
+        state.extend_lease(Peer::from(1), 100);
+        assert!(state.holds_lease(1099.0));
+        assert!(!state.holds_lease(1100.0));
+
+        // Handing leadership over gives the lease up
+        state.transfer_target = Some(Peer::from(3));
+        assert!(!state.holds_lease(1050.0));
+    }
+}
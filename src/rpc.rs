@@ -6,8 +6,9 @@ use wasm_bindgen::JsCast;
 use web_sys::MessageEvent;
 
 use super::{
-    raft::{Peer, Role},
-    Node,
+    log::{Entry, Snapshot},
+    raft::Peer,
+    Node, NodeState,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -26,12 +27,32 @@ pub enum Recipient {
 #[derive(Serialize, Deserialize)] //FromWasmAbi)]
 pub enum Message<T> {
     PeerAdded,
+    /// Like `PeerAdded`, for a node that should only ever be a learner
+    LearnerAdded,
     PeerRemoved,
     PeerSet(HashSet<Peer>),
 
+    /// Sent before a `VoteRequest`, to check whether an election could be won
+    /// without incrementing the term
+    PreVoteRequest {
+        term: u32,
+        candidate: Peer,
+        last_log_index: u32,
+        last_log_term: u32,
+    },
+    PreVoteResponse {
+        term: u32,
+        candidate: Peer,
+        follower: Peer,
+    },
+
+    /// Only granted if the candidate's log is at least as up to date as the
+    /// voter's, judged by `last_log_term` and then `last_log_index`
     VoteRequest {
         term: u32,
         candidate: Peer,
+        last_log_index: u32,
+        last_log_term: u32,
     },
     VoteResponse {
         term: u32,
@@ -39,14 +60,66 @@ pub enum Message<T> {
         follower: Peer,
     },
 
-    Heartbeat {
+    /// Sent by the leader to replicate log entries (also used as the leader
+    /// heartbeat, with no entries)
+    AppendEntries {
+        term: u32,
+        prev_log_index: u32,
+        prev_log_term: u32,
+        entries: Vec<Entry<T>>,
+        leader_commit: u32,
+        /// Echoed back in the response, so the leader knows which round of
+        /// heartbeats was acknowledged (for reads)
+        round: u64,
+    },
+    AppendEntriesResponse {
+        term: u32,
+        success: bool,
+        /// The last index known to match the leader's log (on success)
+        match_index: u32,
+        /// Where the leader should resume sending from (on failure)
+        conflict_index: u32,
+        conflict_term: Option<u32>,
+        round: u64,
+    },
+
+    /// Sent by the leader in place of `AppendEntries` when a peer needs
+    /// entries that have already been compacted. Acknowledged with an
+    /// `AppendEntriesResponse` matching up to the snapshot's index.
+    InstallSnapshot {
+        term: u32,
+        snapshot: Box<Snapshot>,
+    },
+
+    /// Sent by the leader to hand over leadership: the recipient starts an
+    /// election straight away, without waiting for its election timeout
+    TimeoutNow {
+        term: u32,
+    },
+
+    /// Sent to the leader by a node that isn't the leader, to propose a
+    /// payload as `seq` in the proposer's client `session` (see
+    /// [`EntryPayload::Session`](crate::EntryPayload::Session))
+    Propose {
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+        payload: T,
+    },
+    /// Where the proposal was appended (unless the recipient wasn't the
+    /// leader, in which case it isn't `accepted`)
+    ProposeResponse {
+        session: u64,
+        seq: u64,
+        accepted: bool,
+        index: u32,
         term: u32,
     },
-    // HeartbeatResponse,
     // Unknown,
-    Payload(T),
 }
 
+// TODO: move to serde-wasm-bindgen
+#[allow(deprecated)]
 impl<T> MessageWrapper<T>
 where
     T: Serialize + DeserializeOwned + 'static,
@@ -62,7 +135,7 @@ where
 
 impl<T> Node<T>
 where
-    T: serde::ser::Serialize + serde::de::DeserializeOwned + 'static,
+    T: serde::ser::Serialize + serde::de::DeserializeOwned + Clone + 'static,
 {
     pub(crate) fn send(&self, message: Message<T>, to: Recipient) {
         let message = MessageWrapper {
@@ -76,9 +149,22 @@ where
             .expect("failed to post message");
     }
 
+    /// Send a message once everything written to storage so far is durable
+    pub(crate) fn send_persisted(
+        self: &Arc<Self>,
+        state: &mut NodeState<T>,
+        message: Message<T>,
+        to: Recipient,
+    ) {
+        let node = self.clone();
+        state
+            .storage
+            .flush(Box::new(move || node.send(message, to)));
+    }
+
     pub(crate) fn new_listener(self: Arc<Self>) -> EventListener {
         let node = self.clone();
-        EventListener::new(&*self.channel.clone(), "message", move |event| {
+        EventListener::new(&self.channel.clone(), "message", move |event| {
             let event: &MessageEvent = event.dyn_ref::<MessageEvent>().unwrap_throw();
             node.clone()
                 .on_message(MessageWrapper::from_js(event.data()));
@@ -95,13 +181,91 @@ where
         }
 
         match msg {
-            Message::PeerAdded => self.add_peer(from),
+            Message::PeerAdded | Message::LearnerAdded | Message::PeerRemoved => {}
+            _ => self.clone().readmit_peer(from),
+        }
+
+        match msg {
+            Message::PeerAdded => self.add_peer(from, false),
+            Message::LearnerAdded => self.add_peer(from, true),
             Message::PeerRemoved => self.remove_peer(from),
             Message::PeerSet(peers) => self.reconcile_peers(peers),
-            Message::Heartbeat { term } => self.receive_hearbeat(term, &from),
-            Message::VoteRequest { term, candidate } => {
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+            } => self.receive_append_entries(
+                term,
+                from,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+            ),
+            Message::InstallSnapshot { term, snapshot } => {
+                self.receive_install_snapshot(term, from, *snapshot)
+            }
+            Message::TimeoutNow { term } => self.receive_timeout_now(term, from),
+            Message::Propose {
+                session,
+                seq,
+                settled_below,
+                payload,
+            } => self.receive_proposal(session, seq, settled_below, payload, from),
+            Message::ProposeResponse {
+                session,
+                seq,
+                accepted,
+                index,
+                term,
+            } => self.receive_proposal_response(session, seq, accepted, index, term),
+            Message::AppendEntriesResponse {
+                term,
+                success,
+                match_index,
+                conflict_index,
+                conflict_term,
+                round,
+            } => self.receive_append_entries_response(
+                term,
+                from,
+                success,
+                match_index,
+                conflict_index,
+                conflict_term,
+                round,
+            ),
+            Message::PreVoteRequest {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if !self.is(&candidate) {
+                    self.receive_pre_vote_request(term, candidate, last_log_index, last_log_term)
+                }
+            }
+            Message::PreVoteResponse {
+                term,
+                candidate,
+                follower,
+            } => {
+                if self.is(&candidate) {
+                    self.receive_pre_vote(term, follower);
+                }
+            }
+            Message::VoteRequest {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
                 if !self.is(&candidate) {
-                    self.receive_vote_request(term, candidate)
+                    self.receive_vote_request(term, candidate, last_log_index, last_log_term)
                 }
             }
             Message::VoteResponse {
@@ -113,7 +277,6 @@ where
                     self.receive_vote(term, follower);
                 }
             }
-            Message::Payload(payload) => self.call_on_received(payload),
         }
     }
 }
@@ -0,0 +1,233 @@
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    propose::{ProposeError, Proposed},
+    NodeState,
+};
+
+/// Recent results for each client session, so that a proposal which is
+/// retried (and so appended more than once) is only applied once. Built up
+/// deterministically from the log on every node, and carried in snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sessions {
+    active: HashMap<u64, Session>,
+    /// The index each expired session expired at. Their entries are never
+    /// applied again, as a late retry can't be told apart from a new
+    /// proposal. Forgotten after another `session_expiry` entries, by which
+    /// point retries are long over.
+    expired: HashMap<u64, u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Session {
+    /// The client has settled every proposal below this sequence number, so
+    /// none of them is applied (again)
+    settled_below: u64,
+    /// The result of every proposal applied from `settled_below` onward
+    results: BTreeMap<u64, Proposed>,
+    /// Index of the session's latest entry, including duplicates
+    last_index: u32,
+}
+
+/// What to do with a session's entry
+#[derive(Debug, PartialEq)]
+pub(crate) enum SessionCheck {
+    /// Not applied before
+    Apply,
+    /// Already applied, with this result
+    Duplicate(Proposed),
+    /// The client already settled it, so it's too late to apply
+    Settled,
+    /// The session expired
+    Expired,
+}
+
+impl Sessions {
+    /// Check the entry at `index` for `(session, seq)`, counting it as activity
+    /// and dropping results the client has settled since
+    pub(crate) fn check(
+        &mut self,
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+        index: u32,
+    ) -> SessionCheck {
+        if self.expired.contains_key(&session) {
+            return SessionCheck::Expired;
+        }
+
+        let active = self.active.entry(session).or_default();
+        active.last_index = index;
+        if settled_below > active.settled_below {
+            active.settled_below = settled_below;
+            active.results = active.results.split_off(&settled_below);
+        }
+
+        if seq < active.settled_below {
+            SessionCheck::Settled
+        } else if let Some(result) = active.results.get(&seq) {
+            SessionCheck::Duplicate(result.clone())
+        } else {
+            SessionCheck::Apply
+        }
+    }
+
+    /// Cache the result of applying `(session, seq)` at `result.index`
+    pub(crate) fn record(&mut self, session: u64, seq: u64, result: Proposed) {
+        let active = self.active.entry(session).or_default();
+        active.last_index = active.last_index.max(result.index);
+        active.results.insert(seq, result);
+    }
+
+    /// The result of applying `(session, seq)`, if it's been applied (and
+    /// not settled since)
+    pub(crate) fn result(&self, session: u64, seq: u64) -> Option<Proposed> {
+        self.active
+            .get(&session)
+            .and_then(|active| active.results.get(&seq))
+            .cloned()
+    }
+
+    /// Expire sessions with no entries in the `after` entries up to `index`,
+    /// and forget sessions that expired more than `after` entries ago. A
+    /// value of 0 never expires sessions.
+    pub(crate) fn expire(&mut self, index: u32, after: u32) {
+        if after == 0 {
+            return;
+        }
+
+        let expired = &mut self.expired;
+        expired.retain(|_, expired_at| index - *expired_at <= after);
+        self.active.retain(|session, active| {
+            let keep = index - active.last_index <= after;
+            if !keep {
+                expired.insert(*session, index);
+            }
+            keep
+        });
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// Check a session's entry before it's applied, returning whether it
+    /// should be. If it's a duplicate, our own proposal is resolved with the
+    /// original result straight away.
+    pub(crate) fn check_session_entry(
+        &mut self,
+        index: u32,
+        session: u64,
+        seq: u64,
+        settled_below: u64,
+    ) -> bool {
+        let result = match self.sessions.check(session, seq, settled_below, index) {
+            SessionCheck::Apply => return true,
+            SessionCheck::Duplicate(result) => result,
+            SessionCheck::Settled => return false,
+            SessionCheck::Expired => {
+                if session == self.session {
+                    self.new_session();
+                }
+                return false;
+            }
+        };
+
+        if session == self.session {
+            self.resolve_proposal(seq, Ok(result));
+        }
+        false
+    }
+
+    /// Cache the result of applying a session's entry, and resolve it if it
+    /// was our proposal
+    pub(crate) fn record_session_entry(&mut self, session: u64, seq: u64, result: Proposed) {
+        self.sessions.record(session, seq, result.clone());
+        if session == self.session {
+            self.resolve_proposal(seq, Ok(result));
+        }
+    }
+
+    /// Start a new session after ours expired, failing everything proposed
+    /// under the old one
+    pub(crate) fn new_session(&mut self) {
+        self.session = OsRng::new().expect("failed to create RNG").gen();
+        self.next_seq = 1;
+        for (_, proposal) in std::mem::take(&mut self.proposals) {
+            let _ = proposal.done.send(Err(ProposeError::SessionExpired));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(index: u32) -> Proposed {
+        Proposed {
+            index,
+            term: 1,
+            response: None,
+        }
+    }
+
+    #[test]
+    fn retries_return_the_cached_result() {
+        let mut sessions = Sessions::default();
+        assert_eq!(sessions.check(7, 1, 1, 1), SessionCheck::Apply);
+        sessions.record(7, 1, result(1));
+
+        assert_eq!(
+            sessions.check(7, 1, 1, 2),
+            SessionCheck::Duplicate(result(1))
+        );
+        assert_eq!(sessions.check(7, 2, 1, 3), SessionCheck::Apply);
+        sessions.record(7, 2, result(3));
+        assert_eq!(
+            sessions.check(7, 1, 1, 4),
+            SessionCheck::Duplicate(result(1))
+        );
+
+        // Once the client has settled a proposal, its result is dropped
+        assert_eq!(sessions.check(7, 3, 2, 5), SessionCheck::Apply);
+        assert_eq!(sessions.result(7, 1), None);
+        assert_eq!(sessions.check(7, 1, 1, 6), SessionCheck::Settled);
+        assert_eq!(
+            sessions.check(7, 2, 2, 7),
+            SessionCheck::Duplicate(result(3))
+        );
+    }
+
+    #[test]
+    fn earlier_proposals_can_apply_after_later_ones() {
+        let mut sessions = Sessions::default();
+        assert_eq!(sessions.check(7, 2, 1, 1), SessionCheck::Apply);
+        sessions.record(7, 2, result(1));
+
+        // Seq 1 was lost with a leader, and retried after seq 2 applied
+        assert_eq!(sessions.check(7, 1, 1, 2), SessionCheck::Apply);
+        sessions.record(7, 1, result(2));
+        assert_eq!(sessions.result(7, 1), Some(result(2)));
+        assert_eq!(sessions.result(7, 2), Some(result(1)));
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let mut sessions = Sessions::default();
+        sessions.record(7, 1, result(1));
+        sessions.record(8, 1, result(5));
+
+        sessions.expire(11, 8);
+        assert_eq!(sessions.check(7, 2, 2, 12), SessionCheck::Expired);
+        assert_eq!(sessions.check(8, 2, 2, 12), SessionCheck::Apply);
+
+        // Expired sessions are only remembered for so long
+        sessions.expire(19, 8);
+        assert_eq!(sessions.check(7, 2, 2, 19), SessionCheck::Expired);
+        sessions.expire(20, 8);
+        assert!(sessions.expired.is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Application state that is built up by applying committed log entries in
+/// order. Every node applies the same entries in the same order, so every
+/// node's state machine ends up in the same state.
+pub trait StateMachine<T> {
+    /// Value returned from applying a payload
+    type Response: Serialize + DeserializeOwned;
+    /// Serialized form of the whole state machine, used for log compaction
+    type Snapshot: Serialize + DeserializeOwned;
+
+    /// Apply the committed payload at log `index`. Called without the node's
+    /// state locked, so it's fine to use the node from here (e.g. to propose).
+    fn apply(&mut self, index: u32, payload: T) -> Self::Response;
+
+    /// Capture the current state
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replace the current state with a previously captured snapshot
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// Object-safe wrapper around [`StateMachine`], so the node doesn't need to be
+/// generic over the response and snapshot types
+pub(crate) trait DynStateMachine<T> {
+    fn apply(&mut self, index: u32, payload: T) -> serde_json::Value;
+    fn snapshot(&self) -> serde_json::Value;
+    fn restore(&mut self, snapshot: serde_json::Value);
+}
+
+impl<T, S> DynStateMachine<T> for S
+where
+    S: StateMachine<T>,
+{
+    fn apply(&mut self, index: u32, payload: T) -> serde_json::Value {
+        serde_json::to_value(StateMachine::apply(self, index, payload))
+            .expect("failed to serialize response")
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(StateMachine::snapshot(self)).expect("failed to serialize snapshot")
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) {
+        StateMachine::restore(
+            self,
+            serde_json::from_value(snapshot).expect("failed to deserialize snapshot"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u32);
+
+    impl StateMachine<u32> for Counter {
+        type Response = u32;
+        type Snapshot = u32;
+
+        fn apply(&mut self, _index: u32, payload: u32) -> u32 {
+            self.0 += payload;
+            self.0
+        }
+
+        fn snapshot(&self) -> u32 {
+            self.0
+        }
+
+        fn restore(&mut self, snapshot: u32) {
+            self.0 = snapshot;
+        }
+    }
+
+    #[test]
+    fn dyn_state_machine_round_trips() {
+        let mut machine: Box<dyn DynStateMachine<u32>> = Box::new(Counter::default());
+        assert_eq!(machine.apply(1, 2), serde_json::json!(2));
+        assert_eq!(machine.apply(2, 3), serde_json::json!(5));
+
+        let snapshot = machine.snapshot();
+        machine.apply(3, 10);
+        machine.restore(snapshot);
+        assert_eq!(machine.apply(4, 0), serde_json::json!(5));
+    }
+}
@@ -0,0 +1,487 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use super::{HardState, InitialState, Storage};
+use crate::log::{Entry, Snapshot};
+
+const HARD_STATE: &str = "hard_state.json";
+const SNAPSHOT: &str = "snapshot.json";
+const SEGMENT_EXTENSION: &str = "log";
+
+/// A record in a log segment, one per line. Generic over the entry so that
+/// entries can be written by reference.
+#[derive(Serialize, Deserialize)]
+enum Record<E> {
+    Entry(E),
+    /// Every entry from this index onward was deleted
+    Truncate(u32),
+}
+
+struct Segment {
+    path: PathBuf,
+    /// Highest entry index written to this segment (0 if none were)
+    last_index: u32,
+}
+
+/// Storage in a directory on disk. A [`Node`](crate::Node) needs the
+/// browser (for its channel and timers), so this can't back one yet: it's a
+/// standalone [`Storage`] backend, e.g. for tools that read or prepare a
+/// node's log outside the browser.
+///
+/// The log is written to append-only segment files, which are deleted once a
+/// snapshot covers all of their entries. The hard state and snapshot are each
+/// kept in their own file, replaced atomically. Log writes are buffered and
+/// synced to disk together on [`flush`](Storage::flush), so that every entry
+/// in an `AppendEntries` message shares a single fsync.
+///
+/// # Panics
+///
+/// [`Storage`] has no way to report errors, and a write that may not have
+/// reached the disk can't be acknowledged, so the [`Storage`] methods panic
+/// if reading or writing the directory fails. Only [`open`](FileStorage::open)
+/// returns IO errors.
+pub struct FileStorage<T> {
+    dir: PathBuf,
+    segment_size: u64,
+    initial: Option<InitialState<T>>,
+
+    segments: VecDeque<Segment>,
+    next_segment: u64,
+    writer: BufWriter<File>,
+    written: u64,
+
+    /// Highest entry index that's stored (0 if there are none)
+    last_index: u32,
+    hard_state: Option<HardState>,
+    dirty: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T> FileStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Segment size, in bytes, used unless
+    /// [`with_segment_size`](FileStorage::with_segment_size) is called
+    pub const DEFAULT_SEGMENT_SIZE: u64 = 1 << 20;
+
+    /// Open (or create) storage in `dir`, and load its contents
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let hard_state = read_json(&dir.join(HARD_STATE))?.unwrap_or_default();
+        let snapshot = read_json(&dir.join(SNAPSHOT))?;
+
+        // Find segments, in the order they were written
+        let mut numbers = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(number) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+
+        // Replay every segment to rebuild the log
+        let mut entries: Vec<Entry<T>> = Vec::new();
+        let mut segments = VecDeque::new();
+        let count = numbers.len();
+        for (i, number) in numbers.iter().enumerate() {
+            let path = segment_path(&dir, *number);
+            let last_index = replay(&path, &mut entries, i + 1 == count)?;
+            segments.push_back(Segment { path, last_index });
+        }
+
+        // Continue appending to the latest segment
+        let (writer, written, next_segment) = match numbers.last() {
+            Some(number) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(segment_path(&dir, *number))?;
+                let written = file.metadata()?.len();
+                (BufWriter::new(file), written, number + 1)
+            }
+            None => {
+                let path = segment_path(&dir, 0);
+                let writer = create_segment(&dir, &path)?;
+                segments.push_back(Segment {
+                    path,
+                    last_index: 0,
+                });
+                (writer, 0, 1)
+            }
+        };
+
+        Ok(Self {
+            dir,
+            segment_size: Self::DEFAULT_SEGMENT_SIZE,
+            last_index: entries.last().map(|entry| entry.index).unwrap_or(0),
+            initial: Some(InitialState {
+                hard_state,
+                snapshot,
+                entries,
+            }),
+            segments,
+            next_segment,
+            writer,
+            written,
+            hard_state: None,
+            dirty: false,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Start a new segment once the current one reaches `bytes` in size
+    pub fn with_segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    fn write_record<E: Serialize>(&mut self, record: &Record<E>) {
+        if self.written >= self.segment_size {
+            self.roll_segment();
+        }
+
+        let mut line = serde_json::to_vec(record).expect("failed to serialize");
+        line.push(b'\n');
+        self.writer
+            .write_all(&line)
+            .expect("failed to write log segment");
+        self.written += line.len() as u64;
+        self.dirty = true;
+    }
+
+    /// Sync the current segment and start writing to a new one
+    fn roll_segment(&mut self) {
+        self.sync_log();
+
+        let path = segment_path(&self.dir, self.next_segment);
+        self.writer = create_segment(&self.dir, &path).expect("failed to create log segment");
+        self.next_segment += 1;
+        self.written = 0;
+        self.segments.push_back(Segment {
+            path,
+            last_index: 0,
+        });
+    }
+
+    fn sync_log(&mut self) {
+        if self.dirty {
+            self.writer.flush().expect("failed to write log segment");
+            self.writer
+                .get_ref()
+                .sync_data()
+                .expect("failed to sync log segment");
+            self.dirty = false;
+        }
+    }
+}
+
+impl<T> Storage<T> for FileStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn load(&mut self) -> InitialState<T> {
+        self.initial.take().unwrap_or_default()
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) {
+        // Written on the next flush
+        self.hard_state = Some(hard_state.clone());
+    }
+
+    fn append_entries(&mut self, entries: &[Entry<T>]) {
+        if let Some(first) = entries.first() {
+            if first.index <= self.last_index {
+                self.truncate(first.index);
+            }
+        }
+
+        for entry in entries {
+            self.write_record(&Record::Entry(entry));
+            if let Some(segment) = self.segments.back_mut() {
+                segment.last_index = segment.last_index.max(entry.index);
+            }
+            self.last_index = entry.index;
+        }
+    }
+
+    fn truncate(&mut self, index: u32) {
+        if index <= self.last_index {
+            self.write_record(&Record::<&Entry<T>>::Truncate(index));
+            self.last_index = index.saturating_sub(1);
+        }
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) {
+        // The snapshot must be durable before any segments are deleted
+        write_atomic(
+            &self.dir,
+            SNAPSHOT,
+            &serde_json::to_vec(snapshot).expect("failed to serialize"),
+        )
+        .expect("failed to write snapshot");
+    }
+
+    fn compact(&mut self, index: u32) {
+        // Never delete the segment being written to
+        let mut removed = false;
+        while self.segments.len() > 1 && self.segments[0].last_index <= index {
+            if let Some(segment) = self.segments.pop_front() {
+                fs::remove_file(&segment.path).expect("failed to delete log segment");
+                removed = true;
+            }
+        }
+        if removed {
+            sync_dir(&self.dir).expect("failed to sync storage directory");
+        }
+    }
+
+    fn flush(&mut self, done: Box<dyn FnOnce()>) {
+        self.sync_log();
+        if let Some(hard_state) = self.hard_state.take() {
+            write_atomic(
+                &self.dir,
+                HARD_STATE,
+                &serde_json::to_vec(&hard_state).expect("failed to serialize"),
+            )
+            .expect("failed to write hard state");
+        }
+        done()
+    }
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", number, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    sync_dir(dir)?;
+    Ok(BufWriter::new(file))
+}
+
+/// Replay the records in a segment onto `entries`, returning the highest
+/// index written to it. A torn record at the end of the final segment (from a
+/// crash mid-write) is dropped.
+fn replay<T>(path: &Path, entries: &mut Vec<Entry<T>>, last: bool) -> io::Result<u32>
+where
+    T: DeserializeOwned,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut last_index = 0;
+    let mut valid = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+
+        let record = match serde_json::from_str::<Record<Entry<T>>>(&line) {
+            Ok(record) if line.ends_with('\n') => record,
+            _ if last => {
+                OpenOptions::new().write(true).open(path)?.set_len(valid)?;
+                break;
+            }
+            Ok(_) | Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt log segment {}", path.display()),
+                ))
+            }
+        };
+        valid += read as u64;
+
+        match record {
+            Record::Entry(entry) => {
+                last_index = last_index.max(entry.index);
+                truncate_from(entries, entry.index);
+                entries.push(entry);
+            }
+            Record::Truncate(index) => truncate_from(entries, index),
+        }
+    }
+    Ok(last_index)
+}
+
+/// Drop the entries from `index` onward (entries are in index order)
+fn truncate_from<T>(entries: &mut Vec<Entry<T>>, index: u32) {
+    if entries.last().is_some_and(|last| last.index >= index) {
+        let keep = entries.partition_point(|entry| entry.index < index);
+        entries.truncate(keep);
+    }
+}
+
+fn read_json<V: DeserializeOwned>(path: &Path) -> io::Result<Option<V>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Replace the file `name` in `dir` with `contents`, so that a crash leaves
+/// either the old or the new version
+fn write_atomic(dir: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+    let temp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(name))?;
+    sync_dir(dir)
+}
+
+/// Make file creation and renames in `dir` durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{log::EntryPayload, raft::Peer, session::Sessions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An empty directory that's removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "browseraft-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(term: u32, index: u32) -> Entry<String> {
+        Entry {
+            term,
+            index,
+            payload: EntryPayload::Normal(format!("entry {}", index)),
+        }
+    }
+
+    fn flush(storage: &mut FileStorage<String>) {
+        storage.flush(Box::new(|| {}));
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|file| {
+                file.as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    == Some(SEGMENT_EXTENSION)
+            })
+            .count()
+    }
+
+    #[test]
+    fn writes_survive_reopening() {
+        let dir = TempDir::new();
+        let mut storage = FileStorage::open(&dir.0).unwrap();
+        assert!(storage.load().entries.is_empty());
+
+        storage.save_hard_state(&HardState {
+            term: 2,
+            voted_for: Some(Peer::from(4)),
+        });
+        storage.append_entries(&[entry(1, 1), entry(1, 2), entry(1, 3)]);
+        storage.append_entries(&[entry(2, 2)]);
+        flush(&mut storage);
+        drop(storage);
+
+        let initial = FileStorage::<String>::open(&dir.0).unwrap().load();
+        assert_eq!(initial.hard_state.term, 2);
+        assert_eq!(initial.hard_state.voted_for, Some(Peer::from(4)));
+        assert_eq!(initial.entries, vec![entry(1, 1), entry(2, 2)]);
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let dir = TempDir::new();
+        let mut storage = FileStorage::open(&dir.0).unwrap();
+        storage.append_entries(&[entry(1, 1), entry(1, 2)]);
+        flush(&mut storage);
+        drop(storage);
+
+        let path = segment_path(&dir.0, 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"Entry\":{\"term\":1,").unwrap();
+        drop(file);
+
+        let mut storage = FileStorage::<String>::open(&dir.0).unwrap();
+        assert_eq!(storage.load().entries, vec![entry(1, 1), entry(1, 2)]);
+
+        // Appending after recovery still produces a readable segment
+        storage.append_entries(&[entry(1, 3)]);
+        flush(&mut storage);
+        drop(storage);
+        assert_eq!(
+            FileStorage::<String>::open(&dir.0).unwrap().load().entries,
+            vec![entry(1, 1), entry(1, 2), entry(1, 3)]
+        );
+    }
+
+    #[test]
+    fn compaction_deletes_covered_segments() {
+        let dir = TempDir::new();
+        let mut storage = FileStorage::open(&dir.0).unwrap().with_segment_size(1);
+        for index in 1..=5 {
+            storage.append_entries(&[entry(1, index)]);
+        }
+        flush(&mut storage);
+        assert_eq!(segment_count(&dir.0), 5);
+
+        storage.save_snapshot(&Snapshot {
+            index: 3,
+            term: 1,
+            data: serde_json::json!(3),
+            membership: None,
+            sessions: Sessions::default(),
+        });
+        storage.compact(3);
+        assert_eq!(segment_count(&dir.0), 2);
+        drop(storage);
+
+        let initial = FileStorage::<String>::open(&dir.0).unwrap().load();
+        assert_eq!(initial.snapshot.map(|snapshot| snapshot.index), Some(3));
+        assert_eq!(initial.entries, vec![entry(1, 4), entry(1, 5)]);
+    }
+}
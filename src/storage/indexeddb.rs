@@ -0,0 +1,303 @@
+use js_sys::{Array, Promise};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbFactory, IdbObjectStore, IdbRequest, IdbTransactionMode};
+
+use super::{HardState, InitialState, Storage};
+use crate::log::{Entry, Snapshot};
+
+const VERSION: u32 = 1;
+
+const HARD_STATE: &str = "hard_state";
+const SNAPSHOT: &str = "snapshot";
+const ENTRIES: &str = "entries";
+
+/// Storage backed by an IndexedDB database, so that a node's term, vote and
+/// log survive a reload (or crash) of the browsing context.
+///
+/// Every write is its own `readwrite` transaction, and the node waits for
+/// those transactions to complete before voting or acknowledging entries.
+pub struct IndexedDbStorage<T> {
+    db: IdbDatabase,
+    initial: Option<InitialState<T>>,
+    /// First and last index of the entries in the database, if there are any
+    stored: Option<(u32, u32)>,
+    writes: Rc<RefCell<Writes>>,
+    on_abort: Closure<dyn FnMut(JsValue)>,
+}
+
+/// Transactions that haven't completed yet, and the flushes waiting on them
+#[derive(Default)]
+struct Writes {
+    next_id: u64,
+    pending: BTreeSet<u64>,
+    waiting: Vec<(u64, Box<dyn FnOnce()>)>,
+}
+
+impl Writes {
+    /// Mark write `id` as complete, and call any flushes that were only
+    /// waiting on it (or earlier writes)
+    fn complete(writes: &RefCell<Writes>, id: u64) {
+        let ready = {
+            let mut writes = writes.borrow_mut();
+            writes.pending.remove(&id);
+            let oldest = writes.pending.iter().next().copied();
+            let (ready, waiting) = writes
+                .waiting
+                .drain(..)
+                .partition(|(last, _)| match oldest {
+                    Some(oldest) => *last < oldest,
+                    None => true,
+                });
+            writes.waiting = waiting;
+            ready
+        };
+        for (_, done) in ready {
+            done()
+        }
+    }
+}
+
+// TODO: move to serde-wasm-bindgen
+#[allow(deprecated)]
+impl<T> IndexedDbStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Open (or create) the database called `name` using the global
+    /// `indexedDB` factory, and load its contents
+    pub async fn open(name: &str) -> Result<Self, JsValue> {
+        let factory = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("indexedDB"))?
+            .dyn_into::<IdbFactory>()?;
+        Self::open_with_factory(&factory, name).await
+    }
+
+    /// Open (or create) the database called `name` using `factory`, and load
+    /// its contents
+    pub async fn open_with_factory(factory: &IdbFactory, name: &str) -> Result<Self, JsValue> {
+        let request = factory.open_with_u32(name, VERSION)?;
+
+        let upgrade_request = request.clone();
+        let on_upgrade = Closure::wrap(Box::new(move |_event: JsValue| {
+            let db: IdbDatabase = upgrade_request
+                .result()
+                .expect_throw("failed to open database")
+                .unchecked_into();
+            for store in &[HARD_STATE, SNAPSHOT, ENTRIES] {
+                if !db.object_store_names().contains(store) {
+                    db.create_object_store(store)
+                        .expect_throw("failed to create object store");
+                }
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+
+        let db: IdbDatabase = wait(&request).await?.unchecked_into();
+        drop(on_upgrade);
+
+        // Read everything in one transaction
+        let transaction = db.transaction_with_str_sequence(
+            &[HARD_STATE, SNAPSHOT, ENTRIES]
+                .iter()
+                .map(|store| JsValue::from_str(store))
+                .collect::<Array>(),
+        )?;
+        let hard_state = transaction
+            .object_store(HARD_STATE)?
+            .get(&JsValue::from_str(HARD_STATE))?;
+        let snapshot = transaction
+            .object_store(SNAPSHOT)?
+            .get(&JsValue::from_str(SNAPSHOT))?;
+        let entries = transaction.object_store(ENTRIES)?.get_all()?;
+
+        let hard_state = wait(&hard_state).await?;
+        let hard_state = if hard_state.is_undefined() {
+            HardState::default()
+        } else {
+            hard_state.into_serde().map_err(to_js_error)?
+        };
+
+        let snapshot = wait(&snapshot).await?;
+        let snapshot: Option<Snapshot> = if snapshot.is_undefined() {
+            None
+        } else {
+            Some(snapshot.into_serde().map_err(to_js_error)?)
+        };
+
+        // Entries are keyed by index, so they come back in order
+        let entries = wait(&entries)
+            .await?
+            .unchecked_into::<Array>()
+            .iter()
+            .map(|entry| entry.into_serde())
+            .collect::<Result<Vec<Entry<T>>, _>>()
+            .map_err(to_js_error)?;
+        let stored = match (entries.first(), entries.last()) {
+            (Some(first), Some(last)) => Some((first.index, last.index)),
+            _ => None,
+        };
+
+        Ok(Self {
+            db,
+            initial: Some(InitialState {
+                hard_state,
+                snapshot,
+                entries,
+            }),
+            stored,
+            writes: Rc::new(RefCell::new(Writes::default())),
+            on_abort: Closure::wrap(Box::new(|_event: JsValue| {
+                wasm_bindgen::throw_str("IndexedDB write was aborted")
+            }) as Box<dyn FnMut(JsValue)>),
+        })
+    }
+
+    /// Start a transaction on `store`, make some requests with `f`, and track
+    /// the transaction until it completes
+    fn write<F>(&mut self, store: &str, f: F)
+    where
+        F: FnOnce(&IdbObjectStore) -> Result<(), JsValue>,
+    {
+        let transaction = self
+            .db
+            .transaction_with_str_and_mode(store, IdbTransactionMode::Readwrite)
+            .expect_throw("failed to start transaction");
+        f(&transaction
+            .object_store(store)
+            .expect_throw("missing object store"))
+        .expect_throw("failed to write to IndexedDB");
+
+        let id = {
+            let mut writes = self.writes.borrow_mut();
+            let id = writes.next_id;
+            writes.next_id += 1;
+            writes.pending.insert(id);
+            id
+        };
+        let writes = self.writes.clone();
+        let on_complete = Closure::once_into_js(move |_event: JsValue| {
+            Writes::complete(&writes, id);
+        });
+        transaction.set_oncomplete(Some(on_complete.unchecked_ref()));
+        transaction.set_onabort(Some(self.on_abort.as_ref().unchecked_ref()));
+    }
+
+    /// Delete stored entries with indices in `from..=to`
+    fn delete_entries(&mut self, from: u32, to: u32) {
+        if from > to {
+            return;
+        }
+        self.write(ENTRIES, |store| {
+            for index in from..=to {
+                store.delete(&JsValue::from(index))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[allow(deprecated)]
+impl<T> Storage<T> for IndexedDbStorage<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn load(&mut self) -> InitialState<T> {
+        self.initial.take().unwrap_or_default()
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) {
+        let value = JsValue::from_serde(hard_state).expect("failed to serialize");
+        self.write(HARD_STATE, |store| {
+            store
+                .put_with_key(&value, &JsValue::from_str(HARD_STATE))
+                .map(drop)
+        });
+    }
+
+    fn append_entries(&mut self, entries: &[Entry<T>]) {
+        let (first, last) = match (entries.first(), entries.last()) {
+            (Some(first), Some(last)) => (first.index, last.index),
+            _ => return,
+        };
+        self.truncate(first);
+
+        let values = entries
+            .iter()
+            .map(|entry| {
+                (
+                    JsValue::from(entry.index),
+                    JsValue::from_serde(entry).expect("failed to serialize"),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.write(ENTRIES, |store| {
+            for (key, value) in values.iter() {
+                store.put_with_key(value, key)?;
+            }
+            Ok(())
+        });
+
+        self.stored = match self.stored {
+            Some((stored_first, _)) => Some((stored_first.min(first), last)),
+            None => Some((first, last)),
+        };
+    }
+
+    fn truncate(&mut self, index: u32) {
+        if let Some((first, last)) = self.stored {
+            self.delete_entries(index.max(first), last);
+            self.stored = if index > first {
+                Some((first, last.min(index - 1)))
+            } else {
+                None
+            };
+        }
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) {
+        let value = JsValue::from_serde(snapshot).expect("failed to serialize");
+        self.write(SNAPSHOT, |store| {
+            store
+                .put_with_key(&value, &JsValue::from_str(SNAPSHOT))
+                .map(drop)
+        });
+    }
+
+    fn compact(&mut self, index: u32) {
+        if let Some((first, last)) = self.stored {
+            self.delete_entries(first, index.min(last));
+            self.stored = if index < last {
+                Some((first.max(index + 1), last))
+            } else {
+                None
+            };
+        }
+    }
+
+    fn flush(&mut self, done: Box<dyn FnOnce()>) {
+        let mut writes = self.writes.borrow_mut();
+        if writes.pending.is_empty() {
+            drop(writes);
+            done();
+        } else {
+            let last = writes.next_id - 1;
+            writes.waiting.push((last, done));
+        }
+    }
+}
+
+/// Wait for an IndexedDB request to succeed, and get its result
+async fn wait(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    JsFuture::from(promise).await?;
+    request.result()
+}
+
+fn to_js_error(error: serde_json::Error) -> JsValue {
+    JsValue::from_str(&error.to_string())
+}
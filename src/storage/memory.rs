@@ -0,0 +1,81 @@
+use super::{HardState, InitialState, Storage};
+use crate::log::{Entry, Snapshot};
+
+/// Storage that only lives as long as the node (the default). Nothing survives
+/// a reload, so a reloaded node must rejoin as if it were brand new.
+pub struct MemoryStorage<T> {
+    hard_state: HardState,
+    snapshot: Option<Snapshot>,
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> Default for MemoryStorage<T> {
+    fn default() -> Self {
+        Self {
+            hard_state: HardState::default(),
+            snapshot: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Storage<T> for MemoryStorage<T>
+where
+    T: Clone,
+{
+    fn load(&mut self) -> InitialState<T> {
+        InitialState {
+            hard_state: self.hard_state.clone(),
+            snapshot: self.snapshot.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) {
+        self.hard_state = hard_state.clone();
+    }
+
+    fn append_entries(&mut self, entries: &[Entry<T>]) {
+        if let Some(first) = entries.first() {
+            self.truncate(first.index);
+            self.entries.extend_from_slice(entries);
+        }
+    }
+
+    fn truncate(&mut self, index: u32) {
+        self.entries.retain(|entry| entry.index < index);
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) {
+        self.snapshot = Some(snapshot.clone());
+    }
+
+    fn compact(&mut self, index: u32) {
+        self.entries.retain(|entry| entry.index > index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::EntryPayload;
+
+    fn entry(term: u32, index: u32) -> Entry<u32> {
+        Entry {
+            term,
+            index,
+            payload: EntryPayload::Normal(index),
+        }
+    }
+
+    #[test]
+    fn append_replaces_suffix() {
+        let mut storage = MemoryStorage::default();
+        storage.append_entries(&[entry(1, 1), entry(1, 2), entry(1, 3)]);
+        storage.append_entries(&[entry(2, 2)]);
+        assert_eq!(storage.load().entries, vec![entry(1, 1), entry(2, 2)]);
+
+        storage.compact(1);
+        assert_eq!(storage.load().entries, vec![entry(2, 2)]);
+    }
+}
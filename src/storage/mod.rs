@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    log::{Entry, EntryPayload, Log, Snapshot},
+    raft::Peer,
+    NodeState,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod file;
+#[cfg(feature = "indexeddb")]
+mod indexeddb;
+mod memory;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use file::FileStorage;
+#[cfg(feature = "indexeddb")]
+pub use indexeddb::IndexedDbStorage;
+pub use memory::MemoryStorage;
+
+/// The parts of a node's state that must survive a restart for elections to
+/// stay safe
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u32,
+    pub voted_for: Option<Peer>,
+}
+
+/// Everything a [`Storage`] has persisted, loaded when the node is built
+pub struct InitialState<T> {
+    pub hard_state: HardState,
+    pub snapshot: Option<Snapshot>,
+    /// Entries following the snapshot (or from the start of the log, if there
+    /// is no snapshot), in order
+    pub entries: Vec<Entry<T>>,
+}
+
+impl<T> Default for InitialState<T> {
+    fn default() -> Self {
+        Self {
+            hard_state: HardState::default(),
+            snapshot: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// Durable storage for a node's hard state, log and latest snapshot.
+///
+/// The node keeps its own copy of everything in memory and writes through to
+/// the storage, so implementations only need to read when the node is built.
+/// Writes may complete asynchronously, but the node waits on
+/// [`flush`](Storage::flush) before voting or acknowledging entries, and the
+/// leader flushes its own entries whenever it sends them out.
+pub trait Storage<T> {
+    /// Load the persisted state, when the node is built
+    fn load(&mut self) -> InitialState<T>;
+
+    /// Persist the current term and vote
+    fn save_hard_state(&mut self, hard_state: &HardState);
+
+    /// Persist `entries`, replacing any stored entries at or after the index
+    /// of the first one
+    fn append_entries(&mut self, entries: &[Entry<T>]);
+
+    /// Delete every entry from `index` onward
+    fn truncate(&mut self, index: u32);
+
+    /// Persist the latest snapshot, replacing the previous one
+    fn save_snapshot(&mut self, snapshot: &Snapshot);
+
+    /// Delete every entry up to and including `index`, which is covered by
+    /// the latest snapshot
+    fn compact(&mut self, index: u32);
+
+    /// Call `done` once every write made so far is durable. Storage that
+    /// writes synchronously can call it straight away (the default).
+    fn flush(&mut self, done: Box<dyn FnOnce()>) {
+        done()
+    }
+}
+
+impl<T> NodeState<T>
+where
+    T: Clone,
+{
+    /// Replace the in-memory state with what was loaded from storage
+    pub(crate) fn restore(&mut self, initial: InitialState<T>) {
+        let InitialState {
+            hard_state,
+            snapshot,
+            entries,
+        } = initial;
+
+        self.term = hard_state.term;
+        self.voted_for = hard_state.voted_for;
+
+        let (index, term) = snapshot
+            .as_ref()
+            .map(|snapshot| (snapshot.index, snapshot.term))
+            .unwrap_or((0, 0));
+        self.log = Log::restore(index, term, entries);
+        if let Some(ref snapshot) = snapshot {
+            if let Some(ref mut state_machine) = self.state_machine {
+                state_machine.restore(snapshot.data.clone());
+            }
+        }
+        self.commit_index = index;
+        self.last_applied = index;
+        self.sessions = snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.sessions.clone())
+            .unwrap_or_default();
+        self.snapshot = snapshot;
+        self.refresh_membership();
+    }
+
+    pub(crate) fn save_hard_state(&mut self) {
+        let hard_state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        };
+        self.storage.save_hard_state(&hard_state);
+    }
+
+    /// (Leader) Append a new entry to the log for the current term
+    pub(crate) fn append_to_log(&mut self, payload: EntryPayload<T>) -> u32 {
+        let index = self.log.append(self.term, payload);
+        self.storage.append_entries(&self.log.entries_from(index));
+        self.refresh_membership();
+        index
+    }
+
+    /// (Follower) Append entries sent by the leader
+    pub(crate) fn append_from_leader(&mut self, entries: Vec<Entry<T>>) {
+        if let Some(index) = self.log.append_entries(entries) {
+            self.storage.append_entries(&self.log.entries_from(index));
+            self.refresh_membership();
+        }
+    }
+
+    /// Save a snapshot taken from our own state machine, and compact the log
+    /// up to `compact_index`
+    pub(crate) fn save_snapshot(&mut self, snapshot: Snapshot, compact_index: u32) {
+        self.storage.save_snapshot(&snapshot);
+        self.snapshot = Some(snapshot);
+
+        let previous = self.log.compacted_index();
+        self.log.compact(compact_index);
+        if self.log.compacted_index() > previous {
+            self.storage.compact(self.log.compacted_index());
+        }
+    }
+
+    /// (Follower) Save a snapshot sent by the leader, and reset the log to
+    /// follow it
+    pub(crate) fn install_snapshot(&mut self, snapshot: Snapshot) {
+        self.storage.save_snapshot(&snapshot);
+        if !self.log.install_snapshot(snapshot.index, snapshot.term) {
+            self.storage.truncate(snapshot.index + 1);
+        }
+        self.storage.compact(snapshot.index);
+        self.sessions = snapshot.sessions.clone();
+        self.snapshot = Some(snapshot);
+        self.refresh_membership();
+    }
+}
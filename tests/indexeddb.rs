@@ -0,0 +1,105 @@
+//! Run with `npm install` (for `fake-indexeddb`), then
+//! `wasm-pack test --node --features indexeddb`
+#![cfg(all(target_arch = "wasm32", feature = "indexeddb"))]
+
+use browseraft::{
+    Entry, EntryPayload, HardState, IndexedDbStorage, Peer, Sessions, Snapshot, Storage,
+};
+use js_sys::Promise;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_test::*;
+use web_sys::IdbFactory;
+
+#[wasm_bindgen(module = "fake-indexeddb")]
+extern "C" {
+    #[wasm_bindgen(js_name = IDBFactory)]
+    type FakeIdbFactory;
+
+    #[wasm_bindgen(constructor, js_class = "IDBFactory")]
+    fn new() -> FakeIdbFactory;
+}
+
+/// A fresh, empty in-memory IndexedDB
+fn factory() -> IdbFactory {
+    JsValue::from(FakeIdbFactory::new()).unchecked_into()
+}
+
+fn entry(term: u32, index: u32) -> Entry<String> {
+    Entry {
+        term,
+        index,
+        payload: EntryPayload::Normal(format!("entry {}", index)),
+    }
+}
+
+/// Wait until every write made so far has completed
+async fn flush(storage: &mut IndexedDbStorage<String>) {
+    let mut resolve = None;
+    let promise = Promise::new(&mut |res, _rej| resolve = Some(res));
+    let resolve = resolve.unwrap();
+    storage.flush(Box::new(move || {
+        resolve.call0(&JsValue::NULL).unwrap();
+    }));
+    JsFuture::from(promise).await.unwrap();
+}
+
+#[wasm_bindgen_test]
+async fn empty_database_loads_default_state() {
+    let mut storage = IndexedDbStorage::<String>::open_with_factory(&factory(), "empty")
+        .await
+        .unwrap();
+    let initial = storage.load();
+    assert_eq!(initial.hard_state, HardState::default());
+    assert!(initial.snapshot.is_none());
+    assert!(initial.entries.is_empty());
+}
+
+#[wasm_bindgen_test]
+async fn writes_survive_reopening() {
+    let factory = factory();
+    let mut storage = IndexedDbStorage::<String>::open_with_factory(&factory, "node")
+        .await
+        .unwrap();
+    storage.save_hard_state(&HardState {
+        term: 3,
+        voted_for: Some(Peer::from(7)),
+    });
+    storage.append_entries(&[entry(1, 1), entry(1, 2), entry(2, 3)]);
+    storage.append_entries(&[entry(3, 3)]);
+    flush(&mut storage).await;
+
+    let mut reopened = IndexedDbStorage::<String>::open_with_factory(&factory, "node")
+        .await
+        .unwrap();
+    let initial = reopened.load();
+    assert_eq!(initial.hard_state.term, 3);
+    assert_eq!(initial.hard_state.voted_for, Some(Peer::from(7)));
+    assert_eq!(initial.entries, vec![entry(1, 1), entry(1, 2), entry(3, 3)]);
+}
+
+#[wasm_bindgen_test]
+async fn compaction_keeps_snapshot_and_suffix() {
+    let factory = factory();
+    let mut storage = IndexedDbStorage::<String>::open_with_factory(&factory, "compacted")
+        .await
+        .unwrap();
+    storage.append_entries(&[entry(1, 1), entry(1, 2), entry(1, 3), entry(2, 4)]);
+    storage.save_snapshot(&Snapshot {
+        index: 3,
+        term: 1,
+        data: serde_json::json!({ "count": 3 }),
+        membership: None,
+        sessions: Sessions::default(),
+    });
+    storage.compact(2);
+    storage.truncate(4);
+    flush(&mut storage).await;
+
+    let mut reopened = IndexedDbStorage::<String>::open_with_factory(&factory, "compacted")
+        .await
+        .unwrap();
+    let initial = reopened.load();
+    assert_eq!(initial.snapshot.map(|snapshot| snapshot.index), Some(3));
+    assert_eq!(initial.entries, vec![entry(1, 3)]);
+}